@@ -12,8 +12,15 @@ mod oh_my_chess {
     use alloc::format;
     use serde::{Deserialize, Serialize};
     use alloc::string::String;
+    use alloc::vec::Vec;
+    use alloc::boxed::Box;
+    #[cfg(test)]
+    use alloc::collections::BTreeMap;
+    #[cfg(test)]
+    use core::cell::RefCell;
+    use pink::chain_extension::HttpResponse;
     use serde_json_core;
-    use crate::oh_my_chess::Error::{GameSessionIsOver, CouldNotFindKingPiece, SessionNeedsSecondPlayer, ImpossibleError, AlreadyPlayingAsOpponent, ThisSessionContainsAlreadyTwoPlayers, AlreadyInThisGameSession, WrongPlayerAddressArgument, ErrorInsertingToDB, CouldNotUpdateDB, ToIsOccupiedByOneOfYourPiece, PieceSelectedIsNotYours, NoPieceBoardChessFrom, OutOfBoardChessFrom, OutOfBoardChessTo, NonValidMove, NoElementFoundInDB, ErrorFetchingFromDB, NotAuthorized, NotYourTurn, NotInThisGameSession};
+    use crate::oh_my_chess::Error::{GameSessionIsOver, CouldNotFindKingPiece, SessionNeedsSecondPlayer, ImpossibleError, AlreadyPlayingAsOpponent, ThisSessionContainsAlreadyTwoPlayers, AlreadyInThisGameSession, WrongPlayerAddressArgument, ErrorInsertingToDB, CouldNotUpdateDB, ToIsOccupiedByOneOfYourPiece, PieceSelectedIsNotYours, NoPieceBoardChessFrom, OutOfBoardChessFrom, OutOfBoardChessTo, NonValidMove, NoElementFoundInDB, ErrorFetchingFromDB, NotAuthorized, NotYourTurn, NotInThisGameSession, NonValidPromotion, NonValidFen, NoDrawToClaim, NoDrawOffer, NoTakebackRequest, NoMoveToTakeBack, NonValidTimeControl, OutOfTime, NoTimeoutToClaim, ConcurrentModification};
     use scale_info::TypeInfo;
 
 
@@ -41,6 +48,16 @@ mod oh_my_chess {
         ToIsOccupiedByOneOfYourPiece,
         WrongPlayerAddressArgument,
         SessionNeedsSecondPlayer,
+        NonValidPromotion,
+        NonValidFen,
+        NoDrawToClaim,
+        NoDrawOffer,
+        NoTakebackRequest,
+        NoMoveToTakeBack,
+        NonValidTimeControl,
+        OutOfTime,
+        NoTimeoutToClaim,
+        ConcurrentModification,
     }
     pub type Result<T> = core::result::Result<T, Error>;
     pub type Option<T> = core::option::Option<T>;
@@ -50,854 +67,4798 @@ mod oh_my_chess {
         admin: AccountId,
         url: String,
         api_key: String,
+        data_source: String,
+        database: String,
+        game_sessions_collection: String,
+        players_sessions_collection: String,
+        player_ratings_collection: String,
+        lobby_ttl: u64,
+        storage_backend: StorageBackend,
     }
 
     impl OhMyChess {
 
         #[ink(constructor)]
-        pub fn new(url: String, api_key: String) -> Result<Self> {
+        pub fn new(url: String, api_key: String, data_source: String, database: String, game_sessions_collection: String, players_sessions_collection: String, player_ratings_collection: String) -> Result<Self> {
             let instance = Self {
                 admin: Self::env().caller(),
                 url,
                 api_key,
+                data_source,
+                database,
+                game_sessions_collection,
+                players_sessions_collection,
+                player_ratings_collection,
+                lobby_ttl: DEFAULT_LOBBY_TTL,
+                storage_backend: StorageBackend::MongoDB,
             };
             Ok(instance)
         }
 
         #[ink(message)]
-        pub fn start_new_game_session(&self, player: Option<Player>, second_player_address: Option<[u8; 32]>) -> Result<String> {
-            let caller: [u8; 32] = *Self::env().caller().as_ref();
-            let player = player.unwrap_or(Player::White); // Default to White if no player specified
-            let (player_white_address_opt, player_black_address_opt): (Option<[u8; 32]>, Option<[u8; 32]>) = match player {
-                Player::White => (Some(caller), second_player_address),
-                Player::Black => (second_player_address, Some(caller)),
-            };
-
-            let initial_board = [
-                [ // 1st rank (from White's perspective)
-                    Some(ChessCell { piece: Piece::Rook, player: Player::White }),
-                    Some(ChessCell { piece: Piece::Knight, player: Player::White }),
-                    Some(ChessCell { piece: Piece::Bishop, player: Player::White }),
-                    Some(ChessCell { piece: Piece::Queen, player: Player::White }),
-                    Some(ChessCell { piece: Piece::King, player: Player::White }),
-                    Some(ChessCell { piece: Piece::Bishop, player: Player::White }),
-                    Some(ChessCell { piece: Piece::Knight, player: Player::White }),
-                    Some(ChessCell { piece: Piece::Rook, player: Player::White }),
-                ],
-                [ // 2nd rank
-                    Some(ChessCell { piece: Piece::Pawn, player: Player::White }); 8 // All pawns
-                ],
-                [None; 8], // 3rd rank
-                [None; 8], // 4th rank
-                [None; 8], // 5th rank
-                [None; 8], // 6th rank
-                [ // 7th rank
-                    Some(ChessCell { piece: Piece::Pawn, player: Player::Black }); 8 // All pawns
-                ],
-                [ // 8th rank (from Black's perspective)
-                    Some(ChessCell { piece: Piece::Rook, player: Player::Black }),
-                    Some(ChessCell { piece: Piece::Knight, player: Player::Black }),
-                    Some(ChessCell { piece: Piece::Bishop, player: Player::Black }),
-                    Some(ChessCell { piece: Piece::Queen, player: Player::Black }),
-                    Some(ChessCell { piece: Piece::King, player: Player::Black }),
-                    Some(ChessCell { piece: Piece::Bishop, player: Player::Black }),
-                    Some(ChessCell { piece: Piece::Knight, player: Player::Black }),
-                    Some(ChessCell { piece: Piece::Rook, player: Player::Black }),
-                ],
-            ];
-            let game_state = GameStateLobby {
-                board: initial_board,
+        pub fn start_new_game_session(&self, player: Option<ColourChoice>, second_player_address: Option<[u8; 32]>, time_control: Option<TimeControl>, rated: bool) -> Result<String> {
+            let store = &*self.store();
+            let mut game_state = GameStateLobby {
+                board: Self::initial_board(),
                 turn: Player::White, // White starts the game
-                players: PlayersAddressesLobby {
-                    black: player_black_address_opt,
-                    white: player_white_address_opt,
-                },
+                players: Self::players_addresses_lobby(player, second_player_address),
+                colour_draw: Self::colour_draw_from_choice(player),
                 status: GameStatus::Ongoing,
+                created_at: Self::env().block_timestamp(),
+                draw_offer: None,
+                takeback_request: None,
+                clock: Self::clock_from_time_control(time_control)?,
+                rated,
+                version: 0,
+                ratings_update: None,
+                castling_rights: CastlingRights {
+                    white_king_side: true,
+                    white_queen_side: true,
+                    black_king_side: true,
+                    black_queen_side: true,
+                },
+                en_passant: None,
+                halfmove_clock: 0,
+                fullmove_number: 1,
+                moves: Vec::new(),
+                starting_fen: None,
+                position_history: Vec::new(),
+                undo_history: Vec::new(),
             };
-            let inserted_document_id = self.insert_game_session_to_mongodb(game_state)?;
-            if let Some(player_black_address) = player_black_address_opt {
-                self.update_players_sessions_track_in_mongodb(inserted_document_id.clone(), player_black_address)?;
-            }
-            if let Some(player_white_address) = player_white_address_opt {
-                self.update_players_sessions_track_in_mongodb(inserted_document_id.clone(), player_white_address)?;
-            }
+            let position_hash = Self::position_hash(&(game_state.board), &(game_state.turn), &(game_state.castling_rights), &(game_state.en_passant))?;
+            game_state.position_history.push(position_hash);
 
-            Ok(String::from(inserted_document_id.as_str()))
+            Self::create_game_session(store, game_state)
         }
 
         #[ink(message)]
-        pub fn join_session(&self, session_id: String) -> Result<()> {
-            // Attempt to fetch the game session from the database using session_id
-            let mut game_state_lobby = self.find_lobby_game_session_from_mongodb(session_id.clone())?;
-
-            // Check if the caller is already part of the game
-            let caller: [u8; 32] = *Self::env().caller().as_ref();
-            let caller_some = Some(caller);
-
-            if game_state_lobby.players.white == caller_some || game_state_lobby.players.black == caller_some {
-                return Err(AlreadyInThisGameSession);
-            }
-
-            // Check for a free spot in the game and join the game
-            match (game_state_lobby.players.white, game_state_lobby.players.black) {
-                (None, Some(black)) if black == caller => {
-                    // The player is trying to join as white but is already playing as black
-                    return Err(AlreadyPlayingAsOpponent);
-                },
-                (Some(white), None) if white == caller => {
-                    // The player is trying to join as black but is already playing as white
-                    return Err(AlreadyPlayingAsOpponent);
-                },
-                (None, Some(_)) => {
-                    // There is a free spot for white, and the caller is not playing as black
-                    game_state_lobby.players.white = Some(caller);
-                },
-                (Some(_), None) => {
-                    // There is a free spot for black, and the caller is not playing as white
-                    game_state_lobby.players.black = Some(caller);
-                },
-                _ => {
-                    return Err(ThisSessionContainsAlreadyTwoPlayers);
+        pub fn start_new_game_session_from_fen(&self, fen: String, player: Option<ColourChoice>, second_player_address: Option<[u8; 32]>, time_control: Option<TimeControl>) -> Result<String> {
+            let store = &*self.store();
+            let players = Self::players_addresses_lobby(player, second_player_address);
+            let mut game_state = Self::game_state_lobby_from_fen(fen.as_str(), players)?;
+            game_state.colour_draw = Self::colour_draw_from_choice(player);
+            game_state.clock = Self::clock_from_time_control(time_control)?;
+            // Keep the normalized starting position, PGN exports need it to replay the moves
+            game_state.starting_fen = Some(JsonString(Self::game_state_lobby_to_fen(&game_state)));
+
+            // The position may already be over for the side to move
+            if Self::is_check_mate(&(game_state.board), &(game_state.turn), &(game_state.castling_rights), &(game_state.en_passant))? {
+                game_state.status = match game_state.turn {
+                    Player::Black => GameStatus::WonByPlayerWhite,
+                    Player::White => GameStatus::WonByPlayerBlack,
                 }
+            } else if Self::is_stalemate(&(game_state.board), &(game_state.turn), &(game_state.castling_rights), &(game_state.en_passant))? {
+                game_state.status = GameStatus::Stalemate;
+            } else if Self::is_insufficient_material(&(game_state.board)) {
+                game_state.status = GameStatus::Draw;
             }
 
-            let game_state = match (game_state_lobby.players.white, game_state_lobby.players.black) {
-                (Some(white), Some(black)) => GameState {
-                    board: game_state_lobby.board,
-                    turn: game_state_lobby.turn,
-                    players: PlayersAddresses {
-                        white,
-                        black,
-                    },
-                    status: game_state_lobby.status,
-                },
-                // If this branch is reached, then the session is in an invalid state for conversion
-                _ => return Err(ImpossibleError),
-            };
-
-            let session_id_heapless: heapless::String<32> = heapless::String::from(session_id.clone().as_str());
-
-            // Update the game session in the database
-            self.update_game_session_to_mongodb(game_state, session_id.clone())?;
-
-            self.update_players_sessions_track_in_mongodb(session_id_heapless, caller)?;
-
-            Ok(())
+            Self::create_game_session(store, game_state)
         }
 
-        pub fn is_admin(&self) -> bool {
-            Self::env().caller() == self.admin
+        #[ink(message)]
+        pub fn get_fen(&self, session_id: String) -> Result<String> {
+            let store = &*self.store();
+            let game_state_lobby = store.find_game_session(&session_id)?;
+            Ok(Self::game_state_lobby_to_fen(&game_state_lobby))
         }
 
         #[ink(message)]
-        pub fn get_url(&self) -> Result<String> {
-            if self.is_admin() { Ok(self.url.clone()) }
-            else { Err(NotAuthorized) }
+        pub fn export_pgn(&self, session_id: String) -> Result<String> {
+            let store = &*self.store();
+            let game_state_lobby = store.find_game_session(&session_id)?;
+            Self::game_state_lobby_to_pgn(&game_state_lobby)
         }
 
         #[ink(message)]
-        pub fn set_url(&mut self, url: String) -> bool {
-            if Self::env().caller() != self.admin { false }
-            else {
-                self.url = url;
-                true
+        pub fn claim_draw(&self, session_id: String) -> Result<()> {
+            let store = &*self.store();
+            let mut game_state = Self::find_started_game_session(store, &session_id)?;
+
+            // Either player can claim, as long as the position allows it
+            self.check_for_game_state(&game_state)?;
+            self.caller_player(&game_state)?;
+            if !Self::can_claim_draw(&game_state) {
+                return Err(NoDrawToClaim);
             }
+
+            game_state.status = GameStatus::Draw;
+            Self::save_game_session(store, game_state, &session_id)
         }
 
         #[ink(message)]
-        pub fn get_api_key(&self) -> Result<String> {
-            if self.is_admin() { Ok(self.api_key.clone()) }
-            else { Err(NotAuthorized) }
+        pub fn resign(&self, session_id: String) -> Result<()> {
+            let store = &*self.store();
+            let mut game_state = Self::find_started_game_session(store, &session_id)?;
+
+            // Either player can resign at any moment, not only on their turn
+            self.check_for_game_state(&game_state)?;
+            let player = self.caller_player(&game_state)?;
+
+            game_state.status = match player {
+                Player::White => GameStatus::WonByPlayerBlack,
+                Player::Black => GameStatus::WonByPlayerWhite,
+            };
+            Self::save_game_session(store, game_state, &session_id)
         }
 
         #[ink(message)]
-        pub fn set_api_key(&mut self, api_key: String) -> bool {
-            if Self::env().caller() != self.admin { false }
-            else {
-                self.api_key = api_key;
-                true
-            }
+        pub fn offer_draw(&self, session_id: String) -> Result<()> {
+            let store = &*self.store();
+            let mut game_state = Self::find_started_game_session(store, &session_id)?;
+
+            self.check_for_game_state(&game_state)?;
+            let player = self.caller_player(&game_state)?;
+
+            game_state.draw_offer = Some(player);
+            Self::save_game_session(store, game_state, &session_id)
         }
 
         #[ink(message)]
-        pub fn make_move(&self, chess_move: ChessMove, session_id: String) -> Result<()> {
-            let mut game_state = self.find_game_session_from_mongodb(session_id.clone())?;
-            let player = game_state.turn;
+        pub fn accept_draw(&self, session_id: String) -> Result<()> {
+            let store = &*self.store();
+            let mut game_state = Self::find_started_game_session(store, &session_id)?;
 
-            // check validity of the move
             self.check_for_game_state(&game_state)?;
-            Self::check_move_boundaries(&chess_move)?;
-            self.check_caller_turn(&game_state)?;
-            self.check_caller_owns_piece(&game_state, &chess_move)?;
-            Self::check_move_validity_for_piece(&(game_state.board), &player, &chess_move)?;
-            Self::check_if_move_puts_in_check(&(game_state.board), &player, &chess_move)?;
+            self.check_draw_offered_to_caller(&game_state)?;
 
-            // update game_state: board, status
-            Self::update_board_from_move(&mut game_state.board, &player, &chess_move)?;
-            Self::update_turn_and_status(&mut game_state)?;
+            game_state.draw_offer = None;
+            game_state.status = GameStatus::Draw;
+            Self::save_game_session(store, game_state, &session_id)
+        }
 
-            // update mongodb
-            self.update_game_session_to_mongodb(game_state, session_id)
+        #[ink(message)]
+        pub fn decline_draw(&self, session_id: String) -> Result<()> {
+            let store = &*self.store();
+            let mut game_state = Self::find_started_game_session(store, &session_id)?;
+
+            self.check_for_game_state(&game_state)?;
+            self.check_draw_offered_to_caller(&game_state)?;
+
+            game_state.draw_offer = None;
+            Self::save_game_session(store, game_state, &session_id)
         }
 
-        fn check_for_game_state(&self, game_state: &GameState) -> Result<()> {
-            if game_state.status == GameStatus::Ongoing {
-                Ok(())
-            } else {
-                Err(GameSessionIsOver)
+        // Only the opponent of the offering player can answer a draw offer
+        fn check_draw_offered_to_caller(&self, game_state: &GameState) -> Result<()> {
+            let player = self.caller_player(game_state)?;
+            if game_state.draw_offer != Some(Self::opposing_player(&player)) {
+                return Err(NoDrawOffer);
             }
+            Ok(())
         }
 
-        fn check_if_move_puts_in_check(board: &Board, player: &Player, chess_move: &ChessMove) -> Result<()> {
-            let mut temp_game_state = board.clone();
-            Self::update_board_from_move(&mut temp_game_state, &player, &chess_move)?;
+        #[ink(message)]
+        pub fn request_takeback(&self, session_id: String) -> Result<()> {
+            let store = &*self.store();
+            let mut game_state = Self::find_started_game_session(store, &session_id)?;
 
-            // Check if the move has put the current player's king in check
-            if Self::is_in_check(&temp_game_state, player)? {
-                return Err(NonValidMove);
+            self.check_for_game_state(&game_state)?;
+            let player = self.caller_player(&game_state)?;
+            if game_state.undo_history.len() < Self::takeback_length(&game_state, &player) {
+                return Err(NoMoveToTakeBack);
             }
-            Ok(())
+
+            game_state.takeback_request = Some(player);
+            Self::save_game_session(store, game_state, &session_id)
         }
 
-        fn update_turn_and_status(game_state: &mut GameState) -> Result<()> {
-            let current_player = game_state.turn;
-            let opposing_player = match current_player {
-                Player::Black => Player::White,
-                Player::White => Player::Black,
-            };
+        #[ink(message)]
+        pub fn accept_takeback(&self, session_id: String) -> Result<()> {
+            let store = &*self.store();
+            let mut game_state = Self::find_started_game_session(store, &session_id)?;
 
-            if Self::is_check_mate(&(game_state.board), &opposing_player)? {
-                game_state.status = match current_player {
-                    Player::Black => GameStatus::WonByPlayerBlack,
-                    Player::White => GameStatus::WonByPlayerWhite,
-                }
+            self.check_for_game_state(&game_state)?;
+            let player = self.caller_player(&game_state)?;
+            let requesting_player = Self::opposing_player(&player);
+            if game_state.takeback_request != Some(requesting_player) {
+                return Err(NoTakebackRequest);
             }
-            game_state.turn = opposing_player;
+
+            Self::take_back_moves(&mut game_state, &requesting_player)?;
+            // The clocks are back to where they were, neither player is charged for the time spent agreeing on the takeback
+            if let Some(clock) = &mut game_state.clock {
+                clock.last_move_timestamp = Self::env().block_timestamp();
+            }
+            Self::save_game_session(store, game_state, &session_id)
+        }
+
+        // Moves are taken back until the requesting player is to move again:
+        // their last move only, or the last full move when the opponent has already replied
+        fn takeback_length(game_state: &GameState, requesting_player: &Player) -> usize {
+            if game_state.turn == *requesting_player { 2 } else { 1 }
+        }
+
+        fn take_back_moves(game_state: &mut GameState, requesting_player: &Player) -> Result<()> {
+            for _ in 0..Self::takeback_length(game_state, requesting_player) {
+                Self::undo_last_move(game_state)?;
+            }
+            game_state.takeback_request = None;
+            game_state.draw_offer = None;
             Ok(())
         }
 
-        fn update_board_from_move(board: &mut Board, player: &Player, chess_move: &ChessMove) -> Result<()> {
+        // Everything update_board_from_move and the state updates of make_move lose, to restore the position exactly
+        fn undo_record_from_move(game_state: &GameState, chess_move: &ChessMove) -> Result<UndoRecord> {
             let (fx, fy) = chess_move.from;
             let (tx, ty) = chess_move.to;
+            let moved_piece = match game_state.board[fx as usize][fy as usize] {
+                Some(chess_cell) => chess_cell.piece,
+                None => return Err(NoPieceBoardChessFrom),
+            };
 
-            let chess_cell_option = board[fx as usize][fy as usize].take(); // Directly take the value out
-            if let Some(ChessCell { piece: Piece::Pawn, .. }) = &chess_cell_option {
-                // Check if the pawn reaches the end and should be promoted to a queen
-                if tx == 7 || tx == 0 {
-                    board[tx as usize][ty as usize] = Some(ChessCell { piece: Piece::Queen, player: *player });
-                } else {
-                    board[tx as usize][ty as usize] = chess_cell_option;
-                }
+            // An en passant capture takes the pawn beside the target square
+            let captured = if moved_piece == Piece::Pawn && game_state.en_passant == Some(chess_move.to) {
+                game_state.board[fx as usize][ty as usize]
             } else {
-                board[tx as usize][ty as usize] = chess_cell_option;
+                game_state.board[tx as usize][ty as usize]
+            };
+
+            Ok(UndoRecord {
+                chess_move: chess_move.clone(),
+                moved_piece,
+                captured,
+                castling_rights: game_state.castling_rights,
+                en_passant: game_state.en_passant,
+                halfmove_clock: game_state.halfmove_clock,
+                clock_remaining: game_state.clock.as_ref().map(|clock| (clock.white_remaining, clock.black_remaining)),
+            })
+        }
+
+        fn undo_last_move(game_state: &mut GameState) -> Result<()> {
+            let undo_record = game_state.undo_history.pop().ok_or(NoMoveToTakeBack)?;
+            let player = Self::opposing_player(&(game_state.turn));
+            let (fx, fy) = undo_record.chess_move.from;
+            let (tx, ty) = undo_record.chess_move.to;
+            let board = &mut game_state.board;
+
+            // Put the moving piece back, as a pawn again if it was promoted
+            board[tx as usize][ty as usize] = None;
+            board[fx as usize][fy as usize] = Some(ChessCell { piece: undo_record.moved_piece, player });
+
+            if undo_record.moved_piece == Piece::King && (fy as i8 - ty as i8).abs() == 2 {
+                // Castling: the rook goes back to its corner
+                let rook_from_y = if ty > fy { 7 } else { 0 };
+                let rook_to_y = (fy + ty) / 2;
+                board[tx as usize][rook_from_y] = board[tx as usize][rook_to_y as usize].take();
             }
 
-            // Clear the original location after moving the piece
-            board[fx as usize][fy as usize] = None;
+            if undo_record.moved_piece == Piece::Pawn && undo_record.en_passant == Some(undo_record.chess_move.to) {
+                board[fx as usize][ty as usize] = undo_record.captured;
+            } else {
+                board[tx as usize][ty as usize] = undo_record.captured;
+            }
+
+            game_state.castling_rights = undo_record.castling_rights;
+            game_state.en_passant = undo_record.en_passant;
+            game_state.halfmove_clock = undo_record.halfmove_clock;
+            if let (Some(clock), Some((white_remaining, black_remaining))) = (&mut game_state.clock, undo_record.clock_remaining) {
+                clock.white_remaining = white_remaining;
+                clock.black_remaining = black_remaining;
+            }
+            if player == Player::Black {
+                game_state.fullmove_number = game_state.fullmove_number.saturating_sub(1);
+            }
+            game_state.moves.pop();
+            game_state.position_history.pop();
+            game_state.turn = player;
 
             Ok(())
         }
 
-        pub fn check_caller_owns_piece(&self, game_state: &GameState, chess_move: &ChessMove) -> Result<()> {
-            let player_turn = &game_state.turn;
-
-            let ChessCell{ player: player_piece_from, .. } = match &game_state.board[chess_move.from.0 as usize][chess_move.from.1 as usize] {
-                Some(ref chess_cell) => chess_cell,
-                None => return Err(NoPieceBoardChessFrom), // No piece at source
-            };
+        #[ink(message)]
+        pub fn get_rating(&self, address: [u8; 32]) -> Result<PlayerRating> {
+            let store = &*self.store();
+            store.find_player_rating(address)
+        }
 
-            if *player_turn != *player_piece_from {
-                return Err(PieceSelectedIsNotYours);
+        // Persists the game session. The update that ends a rated game also snapshots the ratings of both players,
+        // which are then applied. The game is over whatever happens to the ratings: any left pending are for apply_ratings.
+        fn save_game_session(store: &dyn GameStore, mut game_state: GameState, session_id: &str) -> Result<()> {
+            if game_state.rated && game_state.ratings_update.is_none() && Self::white_score(&(game_state.status)).is_some() {
+                game_state.ratings_update = Some(RatingsUpdate {
+                    white_before: store.find_player_rating(game_state.players.white)?,
+                    black_before: store.find_player_rating(game_state.players.black)?,
+                    white_applied: false,
+                    black_applied: false,
+                });
             }
+            Self::commit_game_session(store, &mut game_state, session_id)?;
+            let _ = Self::apply_pending_ratings(store, game_state, session_id);
+            Ok(())
+        }
 
-            let ChessCell{ player: player_piece_to, .. } = match &game_state.board[chess_move.to.0 as usize][chess_move.to.1 as usize] {
-                Some(ref chess_cell) => chess_cell,
-                None => return Ok(()), // No piece at source
-            };
+        // Fails if the session changed since it was read, the caller validated against a stale state
+        fn commit_game_session(store: &dyn GameStore, game_state: &mut GameState, session_id: &str) -> Result<()> {
+            let expected_version = game_state.version;
+            game_state.version += 1;
+            store.update_game_session(session_id, expected_version, game_state)
+        }
 
-            if *player_piece_to == *player_piece_from { Err(ToIsOccupiedByOneOfYourPiece) }
-            else { Ok(()) }
+        #[ink(message)]
+        pub fn apply_ratings(&self, session_id: String) -> Result<()> {
+            let store = &*self.store();
+            let game_state = Self::find_started_game_session(store, &session_id)?;
+            Self::apply_pending_ratings(store, game_state, &session_id)
         }
 
-        pub fn check_caller_turn(&self, game_state: &GameState) -> Result<()> {
-            let caller = Self::env().caller();
-            let player = &game_state.turn;
-            let player_address = if *player == Player::Black { game_state.players.black } else { game_state.players.white };
+        // Applies each rating update of a finished rated game not applied yet, recording it in the session right after
+        fn apply_pending_ratings(store: &dyn GameStore, mut game_state: GameState, session_id: &str) -> Result<()> {
+            let (white_score, mut ratings_update) = match (Self::white_score(&(game_state.status)), game_state.ratings_update.clone()) {
+                (Some(white_score), Some(ratings_update)) => (white_score, ratings_update),
+                _ => return Ok(()),
+            };
 
-            if caller != AccountId::from(game_state.players.black) && caller != AccountId::from(game_state.players.white) {
-                // The caller is neither black nor white player, return YourNotInThisGameSession error
-                return Err(NotInThisGameSession);
+            // Both players are rated against the rating their opponent had before the game
+            if !ratings_update.white_applied {
+                Self::apply_rating(store, game_state.players.white, &(ratings_update.black_before), white_score, session_id)?;
+                ratings_update.white_applied = true;
+                game_state.ratings_update = Some(ratings_update.clone());
+                Self::commit_game_session(store, &mut game_state, session_id)?;
             }
-            if AccountId::from(player_address) != caller {
-                return Err(NotYourTurn);
+            if !ratings_update.black_applied {
+                Self::apply_rating(store, game_state.players.black, &(ratings_update.white_before), FIXED_ONE - white_score, session_id)?;
+                ratings_update.black_applied = true;
+                game_state.ratings_update = Some(ratings_update);
+                Self::commit_game_session(store, &mut game_state, session_id)?;
             }
-
             Ok(())
         }
 
-        #[ink(message)]
-        pub fn find_lobby_game_session_from_mongodb(&self, session_id: String) -> Result<GameStateLobby> {
-            let method = String::from("POST"); // HTTP Method for the request
-            let url = format!("{}/action/findOne", self.url);
+        // A rating already updated from this session, which then failed to record it, is left as is
+        fn apply_rating(store: &dyn GameStore, player_address: [u8; 32], opponent_before: &PlayerRating, score: Fixed, session_id: &str) -> Result<()> {
+            let player_rating = store.find_player_rating(player_address)?;
+            if player_rating.last_session.as_ref().map(|last_session| last_session.0.as_str()) == Some(session_id) {
+                return Ok(());
+            }
+            let new_player_rating = PlayerRating {
+                version: player_rating.version + 1,
+                last_session: Some(JsonString(String::from(session_id))),
+                ..Self::glicko2_update(&player_rating, &[(opponent_before.clone(), score)])
+            };
+            store.update_player_rating(player_address, player_rating.version, &new_player_rating)
+        }
 
-            let data = format!(r#"{{
-                "collection":"game_sessions",
-                "database":"hackathon",
-                "dataSource":"Cluster0",
-                "filter": {{"_id": {{"$oid": "{}"}}}},
-                "projection":{{"_id":0,"turn":1,"status":1,"players":1,"board":1}}
-            }}"#, session_id).as_bytes().to_vec();
+        // Score of white in a finished game, None while nobody has won or drawn
+        fn white_score(status: &GameStatus) -> Option<Fixed> {
+            match status {
+                GameStatus::WonByPlayerWhite => Some(FIXED_ONE),
+                GameStatus::WonByPlayerBlack => Some(0),
+                GameStatus::Stalemate | GameStatus::Draw => Some(FIXED_ONE / 2),
+                GameStatus::Ongoing | GameStatus::Cancelled | GameStatus::Expired => None,
+            }
+        }
 
-            // Prepare headers
-            let headers = alloc::vec![
-                (String::from("Content-Type"), String::from("application/json")),
-                (String::from("Access-Control-Request-Headers"), String::from("*")),
-                (String::from("api-key"), self.api_key.clone()),
-            ];
+        // One Glicko-2 rating period over the given games and scores, following Mark Glickman's
+        // "Example of the Glicko-2 system". Contracts cannot use floats, hence the fixed point arithmetic.
+        pub fn glicko2_update(player: &PlayerRating, games: &[(PlayerRating, Fixed)]) -> PlayerRating {
+            let (mu, phi, sigma) = Self::glicko2_scale(player);
+
+            // Estimated variance of the rating from the game outcomes, and improvement over the expected scores
+            let mut variance_inverse = 0;
+            let mut improvement = 0;
+            for (opponent, score) in games {
+                let (opponent_mu, opponent_phi, _) = Self::glicko2_scale(opponent);
+                let g = Self::glicko2_g(opponent_phi);
+                let expected_score = Self::glicko2_expected_score(mu, opponent_mu, g);
+                variance_inverse += Self::fixed_mul(Self::fixed_mul(Self::fixed_mul(g, g), expected_score), FIXED_ONE - expected_score);
+                improvement += Self::fixed_mul(g, score - expected_score);
+            }
+            if variance_inverse == 0 {
+                // No game played: only the deviation grows
+                let phi_star = Self::fixed_sqrt(Self::fixed_mul(phi, phi) + Self::fixed_mul(sigma, sigma));
+                return Self::glicko2_unscale(mu, phi_star, sigma, player.games);
+            }
+            let variance = Self::fixed_div(FIXED_ONE, variance_inverse);
+            let delta = Self::fixed_mul(variance, improvement);
 
-            let response = pink::http_req!(
-                method,
-                url,
-                data,
-                headers
-            );
+            let new_sigma = Self::glicko2_volatility(phi, sigma, variance, delta);
+            let phi_star_squared = Self::fixed_mul(phi, phi) + Self::fixed_mul(new_sigma, new_sigma);
+            let new_phi = Self::fixed_div(FIXED_ONE, Self::fixed_sqrt(Self::fixed_div(FIXED_ONE, phi_star_squared) + Self::fixed_div(FIXED_ONE, variance)));
+            let new_mu = mu + Self::fixed_mul(Self::fixed_mul(new_phi, new_phi), improvement);
 
-            let game_state_res = serde_json_core::from_slice::<FindMongoDBDocumentResult>(response.body.as_slice())
-                .map_err(|_| { ErrorFetchingFromDB })
-                .map(|(mongodb, _)| { mongodb.document })?
-                .ok_or(NoElementFoundInDB);
+            Self::glicko2_unscale(new_mu, new_phi, new_sigma, player.games.saturating_add(games.len() as u32))
+        }
 
-            game_state_res
+        fn glicko2_scale(rating: &PlayerRating) -> (Fixed, Fixed, Fixed) {
+            let mu = Self::fixed_div(rating.rating as Fixed - 1500 * FIXED_ONE, GLICKO2_SCALE);
+            let phi = Self::fixed_div(rating.deviation as Fixed, GLICKO2_SCALE);
+            (mu, phi, rating.volatility as Fixed)
         }
 
-        pub fn find_game_session_from_mongodb(&self, session_id: String) -> Result<GameState> {
-            let fetched_game_state_lobby: GameStateLobby = self.find_lobby_game_session_from_mongodb(session_id)?;
+        fn glicko2_unscale(mu: Fixed, phi: Fixed, sigma: Fixed, games: u32) -> PlayerRating {
+            PlayerRating {
+                rating: (Self::fixed_mul(mu, GLICKO2_SCALE) + 1500 * FIXED_ONE) as i64,
+                deviation: Self::fixed_mul(phi, GLICKO2_SCALE) as i64,
+                volatility: sigma as i64,
+                games,
+                ..PlayerRating::default()
+            }
+        }
 
-            // Check if both player addresses are defined
-            let players_addresses = match (fetched_game_state_lobby.players.white, fetched_game_state_lobby.players.black) {
-                (Some(white), Some(black)) => PlayersAddresses { white, black },
-                _ => return Err(SessionNeedsSecondPlayer),
+        fn glicko2_g(phi: Fixed) -> Fixed {
+            let phi_squared = Self::fixed_mul(phi, phi);
+            Self::fixed_div(FIXED_ONE, Self::fixed_sqrt(FIXED_ONE + Self::fixed_div(3 * phi_squared, PI_SQUARED)))
+        }
+
+        // Bounded away from 0 and 1 so that the variance stays finite between very distant ratings
+        fn glicko2_expected_score(mu: Fixed, opponent_mu: Fixed, g: Fixed) -> Fixed {
+            let expected_score = Self::fixed_div(FIXED_ONE, FIXED_ONE + Self::fixed_exp(-Self::fixed_mul(g, mu - opponent_mu)));
+            expected_score.clamp(GLICKO2_MIN_EXPECTED_SCORE, FIXED_ONE - GLICKO2_MIN_EXPECTED_SCORE)
+        }
+
+        // New volatility, root of f found with the Illinois algorithm
+        fn glicko2_volatility(phi: Fixed, sigma: Fixed, variance: Fixed, delta: Fixed) -> Fixed {
+            let phi_squared = Self::fixed_mul(phi, phi);
+            let delta_squared = Self::fixed_mul(delta, delta);
+            let a = Self::fixed_ln(Self::fixed_mul(sigma, sigma));
+            let tau_squared = Self::fixed_mul(GLICKO2_TAU, GLICKO2_TAU);
+            let f = |x: Fixed| {
+                let exp_x = Self::fixed_exp(x);
+                let denominator = phi_squared + variance + exp_x;
+                Self::fixed_div(Self::fixed_mul(exp_x, delta_squared - phi_squared - variance - exp_x), 2 * Self::fixed_mul(denominator, denominator))
+                    - Self::fixed_div(x - a, tau_squared)
             };
 
-            // Convert to GameState, ensuring all fields are properly populated
-            let game_state = GameState {
-                board: fetched_game_state_lobby.board,
-                turn: fetched_game_state_lobby.turn,
-                players: players_addresses,
-                status: fetched_game_state_lobby.status,
+            let mut x_a = a;
+            let mut x_b = if delta_squared > phi_squared + variance {
+                Self::fixed_ln(delta_squared - phi_squared - variance)
+            } else {
+                let mut k = 1;
+                while f(a - k * GLICKO2_TAU) < 0 && k < GLICKO2_MAX_ITERATIONS as Fixed {
+                    k += 1;
+                }
+                a - k * GLICKO2_TAU
             };
+            let (mut f_a, mut f_b) = (f(x_a), f(x_b));
+
+            let mut iterations = 0;
+            while (x_b - x_a).abs() > GLICKO2_EPSILON && f_b != f_a && iterations < GLICKO2_MAX_ITERATIONS {
+                let x_c = x_a + Self::fixed_div(Self::fixed_mul(x_a - x_b, f_a), f_b - f_a);
+                let f_c = f(x_c);
+                if f_c.signum() * f_b.signum() <= 0 {
+                    x_a = x_b;
+                    f_a = f_b;
+                } else {
+                    f_a /= 2;
+                }
+                x_b = x_c;
+                f_b = f_c;
+                iterations += 1;
+            }
 
-            Ok(game_state)
+            Self::fixed_exp(x_a / 2)
         }
 
-        pub fn update_players_sessions_track_in_mongodb(&self, session_id: heapless::String<32>, player_address: [u8; 32]) -> Result<()> {
-            let method = String::from("POST"); // HTTP Method for the request
-            let player_address_hex_string = Self::bytes_to_hex_string(player_address)?;
-            let url = format!("{}/action/updateOne?_id={}", self.url, player_address_hex_string);
+        fn fixed_mul(a: Fixed, b: Fixed) -> Fixed {
+            a * b / FIXED_ONE
+        }
 
-            let data = format!(r#"{{
-                "collection":"players_sessions_trackers",
-                "database":"hackathon",
-                "dataSource":"Cluster0",
-                "filter":{{ "_id": "{}" }},
-                "update":{{"$push": {{"sessions": "{}"}} }},
-                "upsert":true
-            }}"#, player_address_hex_string, session_id).as_bytes().to_vec();
+        fn fixed_div(a: Fixed, b: Fixed) -> Fixed {
+            a * FIXED_ONE / b
+        }
 
-            // Prepare headers
-            let headers = alloc::vec![
-                (String::from("Content-Type"), String::from("application/json")),
-                (String::from("Access-Control-Request-Headers"), String::from("*")),
-                (String::from("api-key"), self.api_key.clone()),
-            ];
+        fn fixed_sqrt(value: Fixed) -> Fixed {
+            // Integer square root by Newton's method, starting above the root
+            let n = value.max(0) as u128 * FIXED_ONE as u128;
+            if n == 0 {
+                return 0;
+            }
+            let mut root = 1u128 << ((128 - n.leading_zeros()) / 2 + 1);
+            loop {
+                let next = (root + n / root) / 2;
+                if next >= root {
+                    return root as Fixed;
+                }
+                root = next;
+            }
+        }
 
-            let response = pink::http_req!(
-                method,
-                url,
-                data,
-                headers
-            );
+        fn fixed_exp(x: Fixed) -> Fixed {
+            // e^x = 2^k e^r with |r| <= ln(2) / 2, where the Taylor series converges quickly
+            let k = (x + x.signum() * LN_2 / 2) / LN_2;
+            let r = x - k * LN_2;
+            let (mut term, mut sum, mut n) = (FIXED_ONE, FIXED_ONE, 1);
+            while term != 0 {
+                term = term * r / FIXED_ONE / n;
+                sum += term;
+                n += 1;
+            }
 
-            if response.status_code == 200 || response.status_code == 201 { Ok(()) }
-            else { Err(CouldNotUpdateDB) }
+            if k >= 0 { sum << k.min(64) as u32 } else if k > -64 { sum >> (-k) as u32 } else { 0 }
+        }
+
+        fn fixed_ln(x: Fixed) -> Fixed {
+            // x = m 2^k with 1 <= m < 2, then ln(m) = 2 atanh((m - 1) / (m + 1))
+            let (mut m, mut k) = (x.max(1), 0);
+            while m >= 2 * FIXED_ONE {
+                m /= 2;
+                k += 1;
+            }
+            while m < FIXED_ONE {
+                m *= 2;
+                k -= 1;
+            }
+            let z = Self::fixed_div(m - FIXED_ONE, m + FIXED_ONE);
+            let z_squared = Self::fixed_mul(z, z);
+            let (mut term, mut sum, mut n) = (z, 0, 1);
+            while term != 0 {
+                sum += term / n;
+                term = Self::fixed_mul(term, z_squared);
+                n += 2;
+            }
+
+            2 * sum + k * LN_2
         }
 
         #[ink(message)]
-        pub fn find_players_sessions_track_in_mongodb(&self) -> Result<[Option<String>; 10]> {
+        pub fn claim_timeout(&self, session_id: String) -> Result<()> {
+            let store = &*self.store();
+            let mut game_state = Self::find_started_game_session(store, &session_id)?;
+
+            self.check_for_game_state(&game_state)?;
+            let player = self.caller_player(&game_state)?;
+            // Only the clock of the player to move is running
+            let flag_has_fallen = match &(game_state.clock) {
+                Some(clock) => game_state.turn != player && Self::is_flag_fallen(clock, &(game_state.turn), Self::env().block_timestamp()),
+                None => false,
+            };
+            if !flag_has_fallen {
+                return Err(NoTimeoutToClaim);
+            }
+
+            // The flag falling only wins the game for a player who could still have checkmated
+            game_state.status = if !Self::has_mating_material(&(game_state.board), &player) {
+                GameStatus::Draw
+            } else {
+                match player {
+                    Player::White => GameStatus::WonByPlayerWhite,
+                    Player::Black => GameStatus::WonByPlayerBlack,
+                }
+            };
+            Self::save_game_session(store, game_state, &session_id)
+        }
+
+        fn clock_from_time_control(time_control: Option<TimeControl>) -> Result<Option<Clock>> {
+            let time_control = match time_control {
+                Some(time_control) => time_control,
+                None => return Ok(None),
+            };
+            let initial_time = match time_control {
+                TimeControl::Fischer { base, .. } | TimeControl::Bronstein { base, .. } => base,
+                TimeControl::Correspondence { days_per_move } => days_per_move as u64 * MILLISECONDS_PER_DAY,
+            };
+            if initial_time == 0 {
+                return Err(NonValidTimeControl);
+            }
+
+            Ok(Some(Clock {
+                time_control,
+                white_remaining: initial_time,
+                black_remaining: initial_time,
+                last_move_timestamp: Self::env().block_timestamp(),
+            }))
+        }
+
+        fn is_flag_fallen(clock: &Clock, player: &Player, now: u64) -> bool {
+            let remaining = match player {
+                Player::White => clock.white_remaining,
+                Player::Black => clock.black_remaining,
+            };
+            now.saturating_sub(clock.last_move_timestamp) >= remaining
+        }
+
+        // Charges the player who has just moved for their thinking time, then credits the increment or delay of the time control
+        fn update_clock(clock: &mut Clock, player: &Player, now: u64) -> Result<()> {
+            if Self::is_flag_fallen(clock, player, now) {
+                return Err(OutOfTime);
+            }
+
+            let elapsed = now.saturating_sub(clock.last_move_timestamp);
+            let remaining = match player {
+                Player::White => &mut clock.white_remaining,
+                Player::Black => &mut clock.black_remaining,
+            };
+            *remaining = match clock.time_control {
+                TimeControl::Fischer { increment, .. } => *remaining - elapsed + increment,
+                // Bronstein delay gives back the time used, up to the delay
+                TimeControl::Bronstein { delay, .. } => *remaining - elapsed + elapsed.min(delay),
+                // Every move gets the same number of days
+                TimeControl::Correspondence { days_per_move } => days_per_move as u64 * MILLISECONDS_PER_DAY,
+            };
+            clock.last_move_timestamp = now;
+
+            Ok(())
+        }
+
+        // A pending draw offer lapses as soon as the opponent of the offering player moves instead of answering it
+        fn update_draw_offer(draw_offer: &mut Option<Player>, player: &Player) {
+            if *draw_offer == Some(Self::opposing_player(player)) {
+                *draw_offer = None;
+            }
+        }
+
+        fn players_addresses_lobby(player: Option<ColourChoice>, second_player_address: Option<[u8; 32]>) -> PlayersAddressesLobby {
             let caller: [u8; 32] = *Self::env().caller().as_ref();
-            let method = String::from("POST");
-            let player_address_hex_string = Self::bytes_to_hex_string(caller)?;
-            let url = format!("{}/action/findOne?_id={}", self.url, player_address_hex_string);
+            let player = player.unwrap_or(ColourChoice::White); // Default to White if no player specified
+            match player {
+                // With a random colour, the creator sits as white until the colours are drawn
+                ColourChoice::White | ColourChoice::Random => PlayersAddressesLobby { white: Some(caller), black: second_player_address },
+                ColourChoice::Black => PlayersAddressesLobby { white: second_player_address, black: Some(caller) },
+            }
+        }
 
-            let data = format!(r#"{{
-                "collection":"players_sessions_trackers",
-                "database":"hackathon",
-                "dataSource":"Cluster0",
-                "filter":{{"_id": "{}"}},
-                "projection":{{"sessions": 1, "_id": 0}}
-            }}"#, player_address_hex_string).as_bytes().to_vec();
+        fn colour_draw_from_choice(player: Option<ColourChoice>) -> Option<ColourDraw> {
+            match player {
+                Some(ColourChoice::Random) => Some(ColourDraw { random_byte: None, creator_colour: None }),
+                _ => None,
+            }
+        }
 
-            // Prepare headers
-            let headers = alloc::vec![
-                (String::from("Content-Type"), String::from("application/json")),
-                (String::from("Access-Control-Request-Headers"), String::from("*")),
-                (String::from("api-key"), self.api_key.clone()),
-            ];
+        // The colours of a random colour session are drawn once both seats are taken. An invited player holds
+        // a seat from the creation on, but the colours are only drawn when they accept it, out of the creator's reach.
+        fn is_colour_draw_pending(game_state_lobby: &GameStateLobby) -> bool {
+            let is_full = game_state_lobby.players.white.is_some() && game_state_lobby.players.black.is_some();
+            is_full && matches!(game_state_lobby.colour_draw, Some(ColourDraw { random_byte: None, .. }))
+        }
 
-            let response = pink::http_req!(
-                method,
-                url,
-                data,
-                headers
-            );
+        // The lowest bit of a uniformly random byte is an unbiased coin flip. The byte and the outcome are kept
+        // in the session document, so the draw can be audited and cannot be replayed once persisted.
+        fn draw_colours(game_state_lobby: &mut GameStateLobby, random_byte: u8) {
+            let creator_colour = if random_byte & 1 == 0 { Player::White } else { Player::Black };
+            if creator_colour == Player::Black {
+                let players = &mut game_state_lobby.players;
+                core::mem::swap(&mut players.white, &mut players.black);
+            }
+            game_state_lobby.colour_draw = Some(ColourDraw { random_byte: Some(random_byte), creator_colour: Some(creator_colour) });
+        }
 
-            if response.status_code != 200 {
-                return Err(ErrorFetchingFromDB);
+        fn random_byte() -> Result<u8> {
+            pink::ext().getrandom(1).first().copied().ok_or(ImpossibleError)
+        }
+
+        fn create_game_session(store: &dyn GameStore, game_state: GameStateLobby) -> Result<String> {
+            let (player_white_address_opt, player_black_address_opt) = (game_state.players.white, game_state.players.black);
+
+            let inserted_document_id = store.insert_game_session(&game_state)?;
+            if let Some(player_black_address) = player_black_address_opt {
+                store.track_session(player_black_address, &inserted_document_id)?;
+            }
+            if let Some(player_white_address) = player_white_address_opt {
+                store.track_session(player_white_address, &inserted_document_id)?;
             }
 
-            let sessions_vec = serde_json_core::from_slice::<FindMongoDBTrackDocumentResult>(&response.body)
-                .map_err(|_| { ErrorFetchingFromDB })
-                .map(|(mongodb, _)| { mongodb.document })?;
+            Ok(inserted_document_id)
+        }
 
-            let sessions_vec = if let Some(vec) = sessions_vec {
-                vec
-            } else {
-                let mut sessions_array: [Option<String>; 10] = Default::default();
-                for i in 0..sessions_array.len() {
-                    sessions_array[i] = None;
-                }
-                return Ok(sessions_array);
+        #[ink(message)]
+        pub fn cancel_session(&self, session_id: String) -> Result<()> {
+            let store = &*self.store();
+            let game_state_lobby = store.find_game_session(&session_id)?;
+            if game_state_lobby.status != GameStatus::Ongoing {
+                return Err(GameSessionIsOver);
+            }
+
+            // Only the creator can cancel, as long as nobody has taken the empty seat
+            let caller: [u8; 32] = *Self::env().caller().as_ref();
+            let creator = match (game_state_lobby.players.white, game_state_lobby.players.black) {
+                (Some(white), None) => white,
+                (None, Some(black)) => black,
+                _ => return Err(ThisSessionContainsAlreadyTwoPlayers),
             };
+            if creator != caller {
+                return Err(NotInThisGameSession);
+            }
 
-            let mut sessions_array: [Option<String>; 10] = Default::default();
-            for (index, session) in sessions_vec.sessions.iter().enumerate() {
-                if index < sessions_array.len() {
-                    sessions_array[index] = Some(String::from(session.as_str())); // Fill the array up to its capacity or the number of elements in the Vec
-                } else {
-                    break; // If the Vec has more elements than the array can hold, stop filling the array
+            store.update_game_session_status(&session_id, game_state_lobby.version, &GameStatus::Cancelled)?;
+            store.untrack_session(creator, &session_id)
+        }
+
+        // Marks a batch of lobbies still waiting for a second player after the lobby TTL as expired, returns how many were found
+        #[ink(message)]
+        pub fn expire_stale_lobbies(&self) -> Result<u32> {
+            let store = &*self.store();
+            if !self.is_admin() {
+                return Err(NotAuthorized);
+            }
+
+            let created_before = Self::env().block_timestamp().saturating_sub(self.lobby_ttl);
+            let stale_lobbies = store.find_lobbies(&LobbyQuery::Stale { created_before }, 0, STALE_LOBBIES_BATCH_SIZE)?;
+            let mut expired_lobbies = 0;
+            for stale_lobby in stale_lobbies.iter() {
+                // Joined or cancelled since it was found, the lobby is left to whoever changed it
+                match store.update_game_session_status(stale_lobby.id.0.as_str(), stale_lobby.version, &GameStatus::Expired) {
+                    Err(ConcurrentModification) => continue,
+                    result => result?,
+                }
+                for player_address in [stale_lobby.players.white, stale_lobby.players.black].into_iter().flatten() {
+                    store.untrack_session(player_address, stale_lobby.id.0.as_str())?;
                 }
+                expired_lobbies += 1;
             }
 
-            Ok(sessions_array)
+            Ok(expired_lobbies)
         }
 
-        pub fn update_game_session_to_mongodb(&self, game_state: GameState, session_id: String) -> Result<()> {
-            let method = String::from("POST"); // HTTP Method for the request
-            let url = format!("{}/action/updateOne", self.url);
+        fn is_lobby_stale(&self, game_state_lobby: &GameStateLobby) -> bool {
+            let has_empty_seat = game_state_lobby.players.white.is_none() || game_state_lobby.players.black.is_none();
+            has_empty_seat && Self::env().block_timestamp().saturating_sub(game_state_lobby.created_at) >= self.lobby_ttl
+        }
 
-            let json_game_state: heapless::String<4096> = serde_json_core::ser::to_string(&game_state).map_err(|_| { CouldNotUpdateDB })?;
+        #[ink(message)]
+        pub fn list_open_lobbies(&self, page: u32, filters: LobbyFilters) -> Result<Vec<OpenLobby>> {
+            let store = &*self.store();
+            let created_after = Self::env().block_timestamp().saturating_sub(self.lobby_ttl);
+            let query = LobbyQuery::Open { filters, created_after, excluded_player: None };
+            let lobbies = store.find_lobbies(&query, page.saturating_mul(OPEN_LOBBIES_PAGE_SIZE), OPEN_LOBBIES_PAGE_SIZE)?;
 
-            let data = format!(r#"{{
-                "collection":"game_sessions",
-                "database":"hackathon",
-                "dataSource":"Cluster0",
-                "filter": {{"_id": {{"$oid": "{}"}}}},
-                "update":{{"$set": {}}}
-            }}"#, session_id, json_game_state).as_bytes().to_vec();
+            lobbies.into_iter().map(Self::open_lobby_from_document).collect()
+        }
 
-            // Prepare headers
-            let headers = alloc::vec![
-                (String::from("Content-Type"), String::from("application/json")),
-                (String::from("Access-Control-Request-Headers"), String::from("*")),
-                (String::from("api-key"), self.api_key.clone()),
-            ];
+        // Joins the oldest waiting lobby matching the preferences, or opens a new one with them
+        #[ink(message)]
+        pub fn quick_match(&self, preferences: LobbyFilters) -> Result<String> {
+            let store = &*self.store();
+            let caller: [u8; 32] = *Self::env().caller().as_ref();
+            let created_after = Self::env().block_timestamp().saturating_sub(self.lobby_ttl);
+            let query = LobbyQuery::Open { filters: preferences.clone(), created_after, excluded_player: Some(caller) };
+
+            match store.find_lobbies(&query, 0, 1)?.pop() {
+                Some(lobby) => {
+                    let session_id = lobby.id.0;
+                    self.join_session(session_id.clone())?;
+                    Ok(session_id)
+                },
+                // Without a colour preference, neither player picks it
+                None => {
+                    let colour = match preferences.colour {
+                        Some(Player::White) => ColourChoice::White,
+                        Some(Player::Black) => ColourChoice::Black,
+                        None => ColourChoice::Random,
+                    };
+                    self.start_new_game_session(Some(colour), None, preferences.time_control, preferences.rated.unwrap_or(false))
+                },
+            }
+        }
 
-            let response = pink::http_req!(
-                method,
-                url,
-                data,
-                headers
-            );
+        fn open_lobby_from_document(lobby: LobbyDocument) -> Result<OpenLobby> {
+            let open_seat = match (lobby.players.white, lobby.players.black) {
+                (None, Some(_)) => Player::White,
+                (Some(_), None) => Player::Black,
+                _ => return Err(ImpossibleError),
+            };
 
-            if response.status_code == 200 { Ok(()) }
-            else { Err(CouldNotUpdateDB) }
+            Ok(OpenLobby {
+                session_id: lobby.id.0,
+                open_seat,
+                random_colour: lobby.colour_draw.is_some(),
+                time_control: lobby.clock.map(|clock| clock.time_control),
+                rated: lobby.rated,
+                created_at: lobby.created_at,
+            })
         }
 
-        pub fn insert_game_session_to_mongodb(&self, game_state: GameStateLobby) -> Result<heapless::String<32>> {
-            let method = String::from("POST"); // HTTP Method for the request
-            let url = format!("{}/action/insertOne", self.url);
+        #[ink(message)]
+        pub fn join_session(&self, session_id: String) -> Result<()> {
+            let store = &*self.store();
+            // Attempt to fetch the game session from the database using session_id
+            let mut game_state_lobby = store.find_game_session(&session_id)?;
 
-            let json_game_state: heapless::String<4096> = serde_json_core::ser::to_string(&game_state).map_err(|_| { ErrorInsertingToDB })?;
+            // Cancelled, expired or stale lobbies cannot be joined anymore
+            if game_state_lobby.status != GameStatus::Ongoing || self.is_lobby_stale(&game_state_lobby) {
+                return Err(GameSessionIsOver);
+            }
 
-            let data = format!(r#"{{
-                "collection":"game_sessions",
-                "database":"hackathon",
-                "dataSource":"Cluster0",
-                "document":{}
-            }}"#, json_game_state).as_bytes().to_vec();
+            // Check if the caller is already part of the game
+            let caller: [u8; 32] = *Self::env().caller().as_ref();
+            let caller_some = Some(caller);
 
-            // Prepare headers
-            let headers = alloc::vec![
-                (String::from("Content-Type"), String::from("application/json")),
-                (String::from("Access-Control-Request-Headers"), String::from("*")),
-                (String::from("api-key"), self.api_key.clone()),
-            ];
+            // The player invited to a random colour session joins it by accepting the seat they hold
+            let accepts_invitation = Self::is_colour_draw_pending(&game_state_lobby) && game_state_lobby.players.black == caller_some;
+            if !accepts_invitation && (game_state_lobby.players.white == caller_some || game_state_lobby.players.black == caller_some) {
+                return Err(AlreadyInThisGameSession);
+            }
 
-            let response = pink::http_req!(
-                method,
-                url,
-                data.clone(),
-                headers
-            );
+            // Check for a free spot in the game and join the game
+            match (game_state_lobby.players.white, game_state_lobby.players.black) {
+                _ if accepts_invitation => {},
+                (None, Some(black)) if black == caller => {
+                    // The player is trying to join as white but is already playing as black
+                    return Err(AlreadyPlayingAsOpponent);
+                },
+                (Some(white), None) if white == caller => {
+                    // The player is trying to join as black but is already playing as white
+                    return Err(AlreadyPlayingAsOpponent);
+                },
+                (None, Some(_)) => {
+                    // There is a free spot for white, and the caller is not playing as black
+                    game_state_lobby.players.white = Some(caller);
+                },
+                (Some(_), None) => {
+                    // There is a free spot for black, and the caller is not playing as white
+                    game_state_lobby.players.black = Some(caller);
+                },
+                _ => {
+                    return Err(ThisSessionContainsAlreadyTwoPlayers);
+                }
+            }
 
-            serde_json_core::from_slice::<InsertedMongoDBDocumentResult>(response.body.as_slice())
-                .map_err(|_| { ErrorInsertingToDB })
-                .map(|(inserted_document_mongo, _)| { inserted_document_mongo.insertedId })?
-                .ok_or(ErrorInsertingToDB)
+            if Self::is_colour_draw_pending(&game_state_lobby) {
+                Self::draw_colours(&mut game_state_lobby, Self::random_byte()?);
+            }
+
+            // The clock of the player to move starts running once both seats are taken
+            if let Some(clock) = &mut game_state_lobby.clock {
+                clock.last_move_timestamp = Self::env().block_timestamp();
+            }
+
+            let game_state = match (game_state_lobby.players.white, game_state_lobby.players.black) {
+                (Some(white), Some(black)) => Self::game_state_from_lobby(game_state_lobby, PlayersAddresses { white, black }),
+                // If this branch is reached, then the session is in an invalid state for conversion
+                _ => return Err(ImpossibleError),
+            };
+
+            // Update the game session in the database, unless somebody else took the seat in the meantime
+            Self::save_game_session(store, game_state, &session_id)?;
+
+            // An invited player is tracked since the creation of the session
+            if !accepts_invitation {
+                store.track_session(caller, &session_id)?;
+            }
+
+            Ok(())
         }
 
-        pub fn bytes_to_hex_string(bytes: [u8; 32]) -> Result<heapless::String<95>> {
-            let mut s: heapless::String<95> = heapless::String::new(); // Adjust the size accordingly
+        pub fn initial_board() -> Board {
+            [
+                [ // 1st rank (from White's perspective)
+                    Some(ChessCell { piece: Piece::Rook, player: Player::White }),
+                    Some(ChessCell { piece: Piece::Knight, player: Player::White }),
+                    Some(ChessCell { piece: Piece::Bishop, player: Player::White }),
+                    Some(ChessCell { piece: Piece::Queen, player: Player::White }),
+                    Some(ChessCell { piece: Piece::King, player: Player::White }),
+                    Some(ChessCell { piece: Piece::Bishop, player: Player::White }),
+                    Some(ChessCell { piece: Piece::Knight, player: Player::White }),
+                    Some(ChessCell { piece: Piece::Rook, player: Player::White }),
+                ],
+                [ // 2nd rank
+                    Some(ChessCell { piece: Piece::Pawn, player: Player::White }); 8 // All pawns
+                ],
+                [None; 8], // 3rd rank
+                [None; 8], // 4th rank
+                [None; 8], // 5th rank
+                [None; 8], // 6th rank
+                [ // 7th rank
+                    Some(ChessCell { piece: Piece::Pawn, player: Player::Black }); 8 // All pawns
+                ],
+                [ // 8th rank (from Black's perspective)
+                    Some(ChessCell { piece: Piece::Rook, player: Player::Black }),
+                    Some(ChessCell { piece: Piece::Knight, player: Player::Black }),
+                    Some(ChessCell { piece: Piece::Bishop, player: Player::Black }),
+                    Some(ChessCell { piece: Piece::Queen, player: Player::Black }),
+                    Some(ChessCell { piece: Piece::King, player: Player::Black }),
+                    Some(ChessCell { piece: Piece::Bishop, player: Player::Black }),
+                    Some(ChessCell { piece: Piece::Knight, player: Player::Black }),
+                    Some(ChessCell { piece: Piece::Rook, player: Player::Black }),
+                ],
+            ]
+        }
 
-            for (i, byte) in bytes.iter().enumerate() {
-                s.push_str(&format!("{:02x}", byte)).map_err(|_| { WrongPlayerAddressArgument })?; // Push each byte as a hex string
+        pub fn is_admin(&self) -> bool {
+            Self::env().caller() == self.admin
+        }
+
+        #[ink(message)]
+        pub fn get_url(&self) -> Result<String> {
+            if self.is_admin() { Ok(self.url.clone()) }
+            else { Err(NotAuthorized) }
+        }
+
+        #[ink(message)]
+        pub fn set_url(&mut self, url: String) -> bool {
+            if Self::env().caller() != self.admin { false }
+            else {
+                self.url = url;
+                true
+            }
+        }
+
+        #[ink(message)]
+        pub fn get_api_key(&self) -> Result<String> {
+            if self.is_admin() { Ok(self.api_key.clone()) }
+            else { Err(NotAuthorized) }
+        }
+
+        #[ink(message)]
+        pub fn set_api_key(&mut self, api_key: String) -> bool {
+            if Self::env().caller() != self.admin { false }
+            else {
+                self.api_key = api_key;
+                true
+            }
+        }
+
+        #[ink(message)]
+        pub fn get_data_source(&self) -> Result<String> {
+            if self.is_admin() { Ok(self.data_source.clone()) }
+            else { Err(NotAuthorized) }
+        }
+
+        #[ink(message)]
+        pub fn set_data_source(&mut self, data_source: String) -> bool {
+            if Self::env().caller() != self.admin { false }
+            else {
+                self.data_source = data_source;
+                true
+            }
+        }
+
+        #[ink(message)]
+        pub fn get_database(&self) -> Result<String> {
+            if self.is_admin() { Ok(self.database.clone()) }
+            else { Err(NotAuthorized) }
+        }
+
+        #[ink(message)]
+        pub fn set_database(&mut self, database: String) -> bool {
+            if Self::env().caller() != self.admin { false }
+            else {
+                self.database = database;
+                true
+            }
+        }
+
+        #[ink(message)]
+        pub fn get_game_sessions_collection(&self) -> Result<String> {
+            if self.is_admin() { Ok(self.game_sessions_collection.clone()) }
+            else { Err(NotAuthorized) }
+        }
+
+        #[ink(message)]
+        pub fn set_game_sessions_collection(&mut self, game_sessions_collection: String) -> bool {
+            if Self::env().caller() != self.admin { false }
+            else {
+                self.game_sessions_collection = game_sessions_collection;
+                true
+            }
+        }
+
+        #[ink(message)]
+        pub fn get_players_sessions_collection(&self) -> Result<String> {
+            if self.is_admin() { Ok(self.players_sessions_collection.clone()) }
+            else { Err(NotAuthorized) }
+        }
+
+        #[ink(message)]
+        pub fn set_players_sessions_collection(&mut self, players_sessions_collection: String) -> bool {
+            if Self::env().caller() != self.admin { false }
+            else {
+                self.players_sessions_collection = players_sessions_collection;
+                true
+            }
+        }
+
+        #[ink(message)]
+        pub fn get_player_ratings_collection(&self) -> Result<String> {
+            if self.is_admin() { Ok(self.player_ratings_collection.clone()) }
+            else { Err(NotAuthorized) }
+        }
+
+        #[ink(message)]
+        pub fn set_player_ratings_collection(&mut self, player_ratings_collection: String) -> bool {
+            if Self::env().caller() != self.admin { false }
+            else {
+                self.player_ratings_collection = player_ratings_collection;
+                true
+            }
+        }
+
+        #[ink(message)]
+        pub fn get_lobby_ttl(&self) -> u64 {
+            self.lobby_ttl
+        }
+
+        #[ink(message)]
+        pub fn set_lobby_ttl(&mut self, lobby_ttl: u64) -> bool {
+            if Self::env().caller() != self.admin { false }
+            else {
+                self.lobby_ttl = lobby_ttl;
+                true
+            }
+        }
+
+        #[ink(message)]
+        pub fn get_storage_backend(&self) -> Result<StorageBackend> {
+            if self.is_admin() { Ok(self.storage_backend) }
+            else { Err(NotAuthorized) }
+        }
+
+        #[ink(message)]
+        pub fn set_storage_backend(&mut self, storage_backend: StorageBackend) -> bool {
+            if Self::env().caller() != self.admin { false }
+            else {
+                self.storage_backend = storage_backend;
+                true
+            }
+        }
+
+        #[ink(message)]
+        pub fn make_move(&self, chess_move: ChessMove, session_id: String) -> Result<()> {
+            let store = &*self.store();
+            let mut game_state = Self::find_started_game_session(store, &session_id)?;
+            let player = game_state.turn;
+
+            // check validity of the move
+            self.check_for_game_state(&game_state)?;
+            Self::check_move_boundaries(&chess_move)?;
+            self.check_caller_turn(&game_state)?;
+            self.check_caller_owns_piece(&game_state, &chess_move)?;
+            Self::check_promotion_validity(&(game_state.board), &chess_move)?;
+            Self::check_move_validity_for_piece(&(game_state.board), &player, &(game_state.castling_rights), &(game_state.en_passant), &chess_move)?;
+            Self::check_if_move_puts_in_check(&(game_state.board), &player, &chess_move)?;
+            // a takeback gives back the time spent on the move
+            let undo_record = Self::undo_record_from_move(&game_state, &chess_move)?;
+            if let Some(clock) = &mut game_state.clock {
+                Self::update_clock(clock, &player, Self::env().block_timestamp())?;
+            }
+
+            // the move is written down before it changes the board
+            let san = Self::san_from_move(&(game_state.board), &player, &(game_state.castling_rights), &(game_state.en_passant), &chess_move)?;
+            game_state.moves.push(JsonString(san));
+            game_state.undo_history.push(undo_record);
+
+            // update game_state: move counters, board, castling rights, en passant target, status
+            Self::update_move_counters(&mut game_state, &chess_move);
+            Self::update_board_from_move(&mut game_state.board, &player, &chess_move)?;
+            Self::update_castling_rights(&mut game_state.castling_rights, &chess_move);
+            Self::update_en_passant_target(&mut game_state.en_passant, &(game_state.board), &chess_move);
+            Self::update_draw_offer(&mut game_state.draw_offer, &player);
+            game_state.takeback_request = None; // a pending takeback request referred to the previous position
+            Self::update_turn_and_status(&mut game_state)?;
+
+            // update mongodb
+            Self::save_game_session(store, game_state, &session_id)
+        }
+
+        #[ink(message)]
+        pub fn legal_moves(&self, session_id: String, from: Option<(u8, u8)>) -> Result<Vec<ChessMove>> {
+            let store = &*self.store();
+            let game_state = Self::find_started_game_session(store, &session_id)?;
+            if let Some(from) = from {
+                if from.0 > 7 || from.1 > 7 {
+                    return Err(OutOfBoardChessFrom);
+                }
+            }
+
+            // A finished game has no move left to play
+            if game_state.status != GameStatus::Ongoing {
+                return Ok(Vec::new());
+            }
+
+            let mut legal_moves = Self::legal_moves_for_board(&(game_state.board), &(game_state.turn), &(game_state.castling_rights), &(game_state.en_passant))?;
+            if let Some(from) = from {
+                legal_moves.retain(|legal_move| legal_move.from == from);
+            }
+            Ok(legal_moves)
+        }
+
+        fn check_for_game_state(&self, game_state: &GameState) -> Result<()> {
+            if game_state.status == GameStatus::Ongoing {
+                Ok(())
+            } else {
+                Err(GameSessionIsOver)
+            }
+        }
+
+        fn check_if_move_puts_in_check(board: &Board, player: &Player, chess_move: &ChessMove) -> Result<()> {
+            let mut temp_game_state = *board;
+            Self::update_board_from_move(&mut temp_game_state, player, chess_move)?;
+
+            // Check if the move has put the current player's king in check
+            if Self::is_in_check(&temp_game_state, player)? {
+                return Err(NonValidMove);
+            }
+            Ok(())
+        }
+
+        fn update_turn_and_status(game_state: &mut GameState) -> Result<()> {
+            let current_player = game_state.turn;
+            let opposing_player = Self::opposing_player(&current_player);
+
+            let position_hash = Self::position_hash(&(game_state.board), &opposing_player, &(game_state.castling_rights), &(game_state.en_passant))?;
+            game_state.position_history.push(position_hash);
+
+            if Self::is_check_mate(&(game_state.board), &opposing_player, &(game_state.castling_rights), &(game_state.en_passant))? {
+                game_state.status = match current_player {
+                    Player::Black => GameStatus::WonByPlayerBlack,
+                    Player::White => GameStatus::WonByPlayerWhite,
+                }
+            } else if Self::is_stalemate(&(game_state.board), &opposing_player, &(game_state.castling_rights), &(game_state.en_passant))? {
+                game_state.status = GameStatus::Stalemate;
+            } else if Self::is_insufficient_material(&(game_state.board)) {
+                // Dead position: no sequence of moves can lead to a checkmate
+                game_state.status = GameStatus::Draw;
+            } else if Self::repetition_count(&(game_state.position_history)) >= 5 || game_state.halfmove_clock >= 150 {
+                // Fivefold repetition and the 75-move rule end the game without any claim
+                game_state.status = GameStatus::Draw;
+            }
+            game_state.turn = opposing_player;
+            Ok(())
+        }
+
+        fn update_board_from_move(board: &mut Board, player: &Player, chess_move: &ChessMove) -> Result<()> {
+            let (fx, fy) = chess_move.from;
+            let (tx, ty) = chess_move.to;
+
+            let chess_cell_option = board[fx as usize][fy as usize].take(); // Directly take the value out
+            if let Some(ChessCell { piece: Piece::Pawn, .. }) = &chess_cell_option {
+                // A pawn moving diagonally onto an empty square is an en passant capture: the captured pawn sits beside it
+                if fy != ty && board[tx as usize][ty as usize].is_none() {
+                    board[fx as usize][ty as usize] = None;
+                }
+                // Check if the pawn reaches the end and should be promoted, to a queen unless another piece was chosen
+                if tx == 7 || tx == 0 {
+                    let promotion_piece = chess_move.promotion.unwrap_or(Piece::Queen);
+                    board[tx as usize][ty as usize] = Some(ChessCell { piece: promotion_piece, player: *player });
+                } else {
+                    board[tx as usize][ty as usize] = chess_cell_option;
+                }
+            } else if let Some(ChessCell { piece: Piece::King, .. }) = &chess_cell_option {
+                // A king moving two files is castling: the rook jumps over to the square the king crossed
+                if (fy as i8 - ty as i8).abs() == 2 {
+                    let rook_from_y = if ty > fy { 7 } else { 0 };
+                    let rook_to_y = (fy + ty) / 2;
+                    board[tx as usize][rook_to_y as usize] = board[tx as usize][rook_from_y].take();
+                }
+                board[tx as usize][ty as usize] = chess_cell_option;
+            } else {
+                board[tx as usize][ty as usize] = chess_cell_option;
+            }
+
+            // Clear the original location after moving the piece
+            board[fx as usize][fy as usize] = None;
+
+            Ok(())
+        }
+
+        // A lone king cannot checkmate, nor can either player when the board lacks the material for any checkmate.
+        // Beyond that, mate is assumed possible: a position only dead because its pawns are blocked is not detected.
+        pub fn has_mating_material(board: &Board, player: &Player) -> bool {
+            let lone_king = board.iter().flatten().flatten()
+                .all(|chess_cell| chess_cell.player != *player || chess_cell.piece == Piece::King);
+            !lone_king && !Self::is_insufficient_material(board)
+        }
+
+        // Kings alone, a single minor piece, or bishops all moving on squares of the same colour cannot checkmate
+        pub fn is_insufficient_material(board: &Board) -> bool {
+            let mut knights = 0;
+            let mut bishops_square_colours: Vec<u8> = Vec::new();
+            for (x, row) in board.iter().enumerate() {
+                for (y, cell) in row.iter().enumerate() {
+                    match cell {
+                        Some(ChessCell { piece: Piece::Pawn, .. })
+                        | Some(ChessCell { piece: Piece::Rook, .. })
+                        | Some(ChessCell { piece: Piece::Queen, .. }) => return false,
+                        Some(ChessCell { piece: Piece::Knight, .. }) => knights += 1,
+                        Some(ChessCell { piece: Piece::Bishop, .. }) => bishops_square_colours.push(((x + y) % 2) as u8),
+                        Some(ChessCell { piece: Piece::King, .. }) | None => {},
+                    }
+                }
+            }
+
+            match (knights, bishops_square_colours.as_slice()) {
+                (0, []) | (1, []) => true,
+                (0, [first_colour, other_colours @ ..]) => other_colours.iter().all(|colour| colour == first_colour),
+                _ => false,
+            }
+        }
+
+        // Threefold repetition and the fifty-move rule allow a draw claim
+        fn can_claim_draw(game_state: &GameState) -> bool {
+            Self::repetition_count(&(game_state.position_history)) >= 3 || game_state.halfmove_clock >= 100
+        }
+
+        // How many times the current position, the last one recorded, has occurred
+        fn repetition_count(position_history: &[JsonString]) -> usize {
+            match position_history.last() {
+                Some(current_position) => position_history.iter().filter(|position| *position == current_position).count(),
+                None => 0,
+            }
+        }
+
+        // Positions are the same when the same pieces stand on the same squares, with the same player to move and the same possible moves:
+        // castling rights, and the en passant square only when such a capture can actually be played
+        pub fn position_hash(board: &Board, turn: &Player, castling_rights: &CastlingRights, en_passant: &Option<(u8, u8)>) -> Result<JsonString> {
+            let mut position_bytes: Vec<u8> = Vec::with_capacity(67);
+            for cell in board.iter().flatten() {
+                position_bytes.push(match cell {
+                    Some(ChessCell { piece, player }) => 1 + 2 * (*piece as u8) + (*player as u8),
+                    None => 0,
+                });
+            }
+            position_bytes.push(*turn as u8);
+            position_bytes.push(
+                (castling_rights.white_king_side as u8)
+                    | (castling_rights.white_queen_side as u8) << 1
+                    | (castling_rights.black_king_side as u8) << 2
+                    | (castling_rights.black_queen_side as u8) << 3
+            );
+            let en_passant_file = match en_passant {
+                Some(en_passant_square) => {
+                    let can_capture_en_passant = Self::legal_moves_for_board(board, turn, &CastlingRights::default(), en_passant)?
+                        .iter()
+                        .any(|legal_move| legal_move.to == *en_passant_square
+                            && matches!(board[legal_move.from.0 as usize][legal_move.from.1 as usize], Some(ChessCell { piece: Piece::Pawn, .. })));
+                    if can_capture_en_passant { en_passant_square.1 } else { 8 }
+                },
+                None => 8,
+            };
+            position_bytes.push(en_passant_file);
+
+            // 64-bit FNV-1a, written in hexadecimal: MongoDB would round such large integers through doubles
+            let mut hash: u64 = 0xcbf29ce484222325;
+            for byte in position_bytes {
+                hash ^= byte as u64;
+                hash = hash.wrapping_mul(0x100000001b3);
+            }
+            Ok(JsonString(format!("{:016x}", hash)))
+        }
+
+        // Must run before the move is applied to the board, to tell captures apart
+        fn update_move_counters(game_state: &mut GameState, chess_move: &ChessMove) {
+            let is_capture = game_state.board[chess_move.to.0 as usize][chess_move.to.1 as usize].is_some();
+            let is_pawn_move = matches!(game_state.board[chess_move.from.0 as usize][chess_move.from.1 as usize], Some(ChessCell { piece: Piece::Pawn, .. }));
+
+            game_state.halfmove_clock = if is_capture || is_pawn_move { 0 } else { game_state.halfmove_clock.saturating_add(1) };
+            if game_state.turn == Player::Black {
+                game_state.fullmove_number = game_state.fullmove_number.saturating_add(1);
+            }
+        }
+
+        fn update_en_passant_target(en_passant: &mut Option<(u8, u8)>, board: &Board, chess_move: &ChessMove) {
+            let (fx, fy) = chess_move.from;
+            let (tx, ty) = chess_move.to;
+
+            // Only a two-step pawn push opens an en passant capture, on the square it skipped, for the next move only
+            *en_passant = match board[tx as usize][ty as usize] {
+                Some(ChessCell { piece: Piece::Pawn, .. }) if (fx as i8 - tx as i8).abs() == 2 => Some(((fx + tx) / 2, fy)),
+                _ => None,
+            };
+        }
+
+        fn update_castling_rights(castling_rights: &mut CastlingRights, chess_move: &ChessMove) {
+            // Any move leaving or landing on a king or rook home square (moving it, or capturing the rook) loses the matching rights
+            for square in [chess_move.from, chess_move.to] {
+                match square {
+                    (0, 4) => {
+                        castling_rights.white_king_side = false;
+                        castling_rights.white_queen_side = false;
+                    },
+                    (0, 0) => castling_rights.white_queen_side = false,
+                    (0, 7) => castling_rights.white_king_side = false,
+                    (7, 4) => {
+                        castling_rights.black_king_side = false;
+                        castling_rights.black_queen_side = false;
+                    },
+                    (7, 0) => castling_rights.black_queen_side = false,
+                    (7, 7) => castling_rights.black_king_side = false,
+                    _ => {},
+                }
+            }
+        }
+
+        pub fn check_caller_owns_piece(&self, game_state: &GameState, chess_move: &ChessMove) -> Result<()> {
+            let player_turn = &game_state.turn;
+
+            let ChessCell{ player: player_piece_from, .. } = match &game_state.board[chess_move.from.0 as usize][chess_move.from.1 as usize] {
+                Some(ref chess_cell) => chess_cell,
+                None => return Err(NoPieceBoardChessFrom), // No piece at source
+            };
+
+            if *player_turn != *player_piece_from {
+                return Err(PieceSelectedIsNotYours);
+            }
+
+            let ChessCell{ player: player_piece_to, .. } = match &game_state.board[chess_move.to.0 as usize][chess_move.to.1 as usize] {
+                Some(ref chess_cell) => chess_cell,
+                None => return Ok(()), // No piece at source
+            };
+
+            if *player_piece_to == *player_piece_from { Err(ToIsOccupiedByOneOfYourPiece) }
+            else { Ok(()) }
+        }
+
+        pub fn check_caller_turn(&self, game_state: &GameState) -> Result<()> {
+            if self.caller_player(game_state)? != game_state.turn {
+                return Err(NotYourTurn);
+            }
+
+            Ok(())
+        }
+
+        // Color played by the caller in this game session
+        fn caller_player(&self, game_state: &GameState) -> Result<Player> {
+            let caller = Self::env().caller();
+
+            if caller == AccountId::from(game_state.players.white) {
+                Ok(Player::White)
+            } else if caller == AccountId::from(game_state.players.black) {
+                Ok(Player::Black)
+            } else {
+                // The caller is neither black nor white player, return YourNotInThisGameSession error
+                Err(NotInThisGameSession)
+            }
+        }
+
+        #[ink(message)]
+        pub fn find_lobby_game_session_from_mongodb(&self, session_id: String) -> Result<GameStateLobby> {
+            self.store().find_game_session(&session_id)
+        }
+
+        // The adapter of the storage backend the admin selected
+        fn store(&self) -> Box<dyn GameStore + '_> {
+            match self.storage_backend {
+                StorageBackend::MongoDB => Box::new(MongoDBStore {
+                    url: &self.url,
+                    api_key: &self.api_key,
+                    data_source: &self.data_source,
+                    database: &self.database,
+                    game_sessions_collection: &self.game_sessions_collection,
+                    players_sessions_collection: &self.players_sessions_collection,
+                    player_ratings_collection: &self.player_ratings_collection,
+                }),
+                StorageBackend::LocalCache => Box::new(LocalCacheStore {
+                    database: &self.database,
+                    game_sessions_collection: &self.game_sessions_collection,
+                    players_sessions_collection: &self.players_sessions_collection,
+                    player_ratings_collection: &self.player_ratings_collection,
+                }),
+            }
+        }
+
+        fn find_started_game_session(store: &dyn GameStore, session_id: &str) -> Result<GameState> {
+            let fetched_game_state_lobby: GameStateLobby = store.find_game_session(session_id)?;
+
+            // Check if both player addresses are defined
+            let players_addresses = match (fetched_game_state_lobby.players.white, fetched_game_state_lobby.players.black) {
+                // An invited player has yet to accept a random colour session
+                (Some(_), Some(_)) if Self::is_colour_draw_pending(&fetched_game_state_lobby) => return Err(SessionNeedsSecondPlayer),
+                (Some(white), Some(black)) => PlayersAddresses { white, black },
+                _ => return Err(SessionNeedsSecondPlayer),
+            };
+
+            Ok(Self::game_state_from_lobby(fetched_game_state_lobby, players_addresses))
+        }
+
+        // Both seats are taken once the session is a GameState
+        fn lobby_from_game_state(game_state: GameState) -> GameStateLobby {
+            GameStateLobby {
+                board: game_state.board,
+                turn: game_state.turn,
+                players: PlayersAddressesLobby { white: Some(game_state.players.white), black: Some(game_state.players.black) },
+                colour_draw: game_state.colour_draw,
+                status: game_state.status,
+                created_at: game_state.created_at,
+                draw_offer: game_state.draw_offer,
+                takeback_request: game_state.takeback_request,
+                clock: game_state.clock,
+                rated: game_state.rated,
+                version: game_state.version,
+                ratings_update: game_state.ratings_update,
+                castling_rights: game_state.castling_rights,
+                en_passant: game_state.en_passant,
+                halfmove_clock: game_state.halfmove_clock,
+                fullmove_number: game_state.fullmove_number,
+                moves: game_state.moves,
+                starting_fen: game_state.starting_fen,
+                position_history: game_state.position_history,
+                undo_history: game_state.undo_history,
+            }
+        }
+
+        // Convert to GameState once both seats are taken, ensuring all fields are properly populated
+        fn game_state_from_lobby(game_state_lobby: GameStateLobby, players: PlayersAddresses) -> GameState {
+            GameState {
+                board: game_state_lobby.board,
+                turn: game_state_lobby.turn,
+                players,
+                colour_draw: game_state_lobby.colour_draw,
+                status: game_state_lobby.status,
+                created_at: game_state_lobby.created_at,
+                draw_offer: game_state_lobby.draw_offer,
+                takeback_request: game_state_lobby.takeback_request,
+                clock: game_state_lobby.clock,
+                rated: game_state_lobby.rated,
+                version: game_state_lobby.version,
+                ratings_update: game_state_lobby.ratings_update,
+                castling_rights: game_state_lobby.castling_rights,
+                en_passant: game_state_lobby.en_passant,
+                halfmove_clock: game_state_lobby.halfmove_clock,
+                fullmove_number: game_state_lobby.fullmove_number,
+                moves: game_state_lobby.moves,
+                starting_fen: game_state_lobby.starting_fen,
+                position_history: game_state_lobby.position_history,
+                undo_history: game_state_lobby.undo_history,
+            }
+        }
+
+        #[ink(message)]
+        pub fn find_players_sessions_track_in_mongodb(&self) -> Result<Vec<String>> {
+            let caller: [u8; 32] = *Self::env().caller().as_ref();
+            self.store().find_tracked_sessions(caller)
+        }
+
+        pub fn bytes_to_hex_string(bytes: [u8; 32]) -> Result<heapless::String<95>> {
+            let mut s: heapless::String<95> = heapless::String::new(); // Adjust the size accordingly
+
+            for (i, byte) in bytes.iter().enumerate() {
+                s.push_str(&format!("{:02x}", byte)).map_err(|_| { WrongPlayerAddressArgument })?; // Push each byte as a hex string
+
+                if i < bytes.len() - 1 {
+                    s.push('-').map_err(|_| { WrongPlayerAddressArgument })?; // Separate bytes with a '-'
+                }
+            }
+
+            Ok(s)
+        }
+
+        pub fn game_state_lobby_from_fen(fen: &str, players: PlayersAddressesLobby) -> Result<GameStateLobby> {
+            let fields: Vec<&str> = fen.split_whitespace().collect();
+            // The halfmove clock and fullmove number are often left out
+            if fields.len() != 4 && fields.len() != 6 {
+                return Err(NonValidFen);
+            }
+
+            // Piece placement, from the 8th rank down to the 1st
+            let ranks: Vec<&str> = fields[0].split('/').collect();
+            if ranks.len() != 8 {
+                return Err(NonValidFen);
+            }
+            let mut board: Board = [[None; 8]; 8];
+            for (rank_index, rank) in ranks.iter().enumerate() {
+                let x = 7 - rank_index;
+                let mut y = 0;
+                for symbol in rank.chars() {
+                    if let Some(empty_squares) = symbol.to_digit(10) {
+                        if empty_squares == 0 {
+                            return Err(NonValidFen);
+                        }
+                        y += empty_squares as usize;
+                    } else {
+                        if y >= 8 {
+                            return Err(NonValidFen);
+                        }
+                        board[x][y] = Some(Self::chess_cell_from_fen_symbol(symbol)?);
+                        y += 1;
+                    }
+                    if y > 8 {
+                        return Err(NonValidFen);
+                    }
+                }
+                if y != 8 {
+                    return Err(NonValidFen);
+                }
+            }
+
+            // Each side needs exactly one king, and no pawn can stand on the first or last rank
+            for player in [Player::White, Player::Black] {
+                let kings = board.iter().flatten().filter(|cell| **cell == Some(ChessCell { piece: Piece::King, player })).count();
+                if kings != 1 {
+                    return Err(NonValidFen);
+                }
+            }
+            if board[0].iter().chain(board[7].iter()).any(|cell| matches!(cell, Some(ChessCell { piece: Piece::Pawn, .. }))) {
+                return Err(NonValidFen);
+            }
+
+            let turn = match fields[1] {
+                "w" => Player::White,
+                "b" => Player::Black,
+                _ => return Err(NonValidFen),
+            };
+            let castling_rights = Self::castling_rights_from_fen(fields[2], &board)?;
+            let en_passant = Self::en_passant_from_fen(fields[3], &board, &turn)?;
+            let (halfmove_clock, fullmove_number) = if fields.len() == 6 {
+                (
+                    fields[4].parse::<u16>().map_err(|_| NonValidFen)?,
+                    fields[5].parse::<u16>().map_err(|_| NonValidFen)?,
+                )
+            } else {
+                (0, 1)
+            };
+            if fullmove_number == 0 {
+                return Err(NonValidFen);
+            }
+
+            // The player who has just moved cannot have left their king in check
+            if Self::is_in_check(&board, &Self::opposing_player(&turn))? {
+                return Err(NonValidFen);
+            }
+
+            Ok(GameStateLobby {
+                board,
+                turn,
+                players,
+                colour_draw: None,
+                status: GameStatus::Ongoing,
+                created_at: Self::env().block_timestamp(),
+                draw_offer: None,
+                takeback_request: None,
+                clock: None,
+                rated: false, // Arbitrary starting positions are only for casual play
+                version: 0,
+                ratings_update: None,
+                castling_rights,
+                en_passant,
+                halfmove_clock,
+                fullmove_number,
+                moves: Vec::new(),
+                starting_fen: None,
+                position_history: alloc::vec![Self::position_hash(&board, &turn, &castling_rights, &en_passant)?],
+                undo_history: Vec::new(),
+            })
+        }
+
+        fn chess_cell_from_fen_symbol(symbol: char) -> Result<ChessCell> {
+            let player = if symbol.is_ascii_uppercase() { Player::White } else { Player::Black };
+            let piece = match symbol.to_ascii_lowercase() {
+                'p' => Piece::Pawn,
+                'n' => Piece::Knight,
+                'b' => Piece::Bishop,
+                'r' => Piece::Rook,
+                'q' => Piece::Queen,
+                'k' => Piece::King,
+                _ => return Err(NonValidFen),
+            };
+            Ok(ChessCell { piece, player })
+        }
+
+        fn fen_symbol_from_chess_cell(chess_cell: &ChessCell) -> char {
+            let symbol = match chess_cell.piece {
+                Piece::Pawn => 'p',
+                Piece::Knight => 'n',
+                Piece::Bishop => 'b',
+                Piece::Rook => 'r',
+                Piece::Queen => 'q',
+                Piece::King => 'k',
+            };
+            if chess_cell.player == Player::White { symbol.to_ascii_uppercase() } else { symbol }
+        }
+
+        fn castling_rights_from_fen(field: &str, board: &Board) -> Result<CastlingRights> {
+            let mut castling_rights = CastlingRights::default();
+            if field == "-" {
+                return Ok(castling_rights);
+            }
+
+            for symbol in field.chars() {
+                // A right is only meaningful while the king and the matching rook are still on their home squares
+                let (player, rook_square, castling_right) = match symbol {
+                    'K' => (Player::White, (0, 7), &mut castling_rights.white_king_side),
+                    'Q' => (Player::White, (0, 0), &mut castling_rights.white_queen_side),
+                    'k' => (Player::Black, (7, 7), &mut castling_rights.black_king_side),
+                    'q' => (Player::Black, (7, 0), &mut castling_rights.black_queen_side),
+                    _ => return Err(NonValidFen),
+                };
+                let home_rank = rook_square.0;
+                if *castling_right
+                    || board[home_rank][4] != Some(ChessCell { piece: Piece::King, player })
+                    || board[rook_square.0][rook_square.1] != Some(ChessCell { piece: Piece::Rook, player }) {
+                    return Err(NonValidFen);
+                }
+                *castling_right = true;
+            }
+
+            Ok(castling_rights)
+        }
+
+        fn en_passant_from_fen(field: &str, board: &Board, turn: &Player) -> Result<Option<(u8, u8)>> {
+            if field == "-" {
+                return Ok(None);
+            }
+
+            let (x, y) = Self::square_from_algebraic(field).ok_or(NonValidFen)?;
+            // The square was just skipped by a two-step push of the opponent's pawn, which now stands right past it
+            let (expected_rank, pawn_rank, pawn_player) = match turn {
+                Player::White => (5, 4, Player::Black),
+                Player::Black => (2, 3, Player::White),
+            };
+            if x != expected_rank
+                || board[x as usize][y as usize].is_some()
+                || board[pawn_rank][y as usize] != Some(ChessCell { piece: Piece::Pawn, player: pawn_player }) {
+                return Err(NonValidFen);
+            }
+
+            Ok(Some((x, y)))
+        }
+
+        pub fn game_state_lobby_to_fen(game_state: &GameStateLobby) -> String {
+            let mut fen = String::new();
+
+            // Piece placement, from the 8th rank down to the 1st
+            for x in (0..8).rev() {
+                let mut empty_squares = 0;
+                for y in 0..8 {
+                    match &game_state.board[x][y] {
+                        Some(chess_cell) => {
+                            if empty_squares > 0 {
+                                fen.push_str(&format!("{}", empty_squares));
+                                empty_squares = 0;
+                            }
+                            fen.push(Self::fen_symbol_from_chess_cell(chess_cell));
+                        },
+                        None => empty_squares += 1,
+                    }
+                }
+                if empty_squares > 0 {
+                    fen.push_str(&format!("{}", empty_squares));
+                }
+                if x > 0 {
+                    fen.push('/');
+                }
+            }
+
+            fen.push_str(match game_state.turn {
+                Player::White => " w ",
+                Player::Black => " b ",
+            });
+
+            let castling_rights = &game_state.castling_rights;
+            for (has_castling_right, symbol) in [
+                (castling_rights.white_king_side, 'K'),
+                (castling_rights.white_queen_side, 'Q'),
+                (castling_rights.black_king_side, 'k'),
+                (castling_rights.black_queen_side, 'q'),
+            ] {
+                if has_castling_right {
+                    fen.push(symbol);
+                }
+            }
+            if *castling_rights == CastlingRights::default() {
+                fen.push('-');
+            }
+
+            match game_state.en_passant {
+                Some(square) => fen.push_str(&format!(" {}", Self::square_to_algebraic(square))),
+                None => fen.push_str(" -"),
+            }
+
+            fen.push_str(&format!(" {} {}", game_state.halfmove_clock, game_state.fullmove_number));
+            fen
+        }
+
+        // Standard Algebraic Notation of a legal move, played by `player` from the given position
+        pub fn san_from_move(board: &Board, player: &Player, castling_rights: &CastlingRights, en_passant: &Option<(u8, u8)>, chess_move: &ChessMove) -> Result<String> {
+            let (from, to) = (chess_move.from, chess_move.to);
+            let piece = match board[from.0 as usize][from.1 as usize] {
+                Some(ChessCell { piece, .. }) => piece,
+                None => return Err(NoPieceBoardChessFrom),
+            };
+
+            let mut san = String::new();
+            if piece == Piece::King && (from.1 as i8 - to.1 as i8).abs() == 2 {
+                san.push_str(if to.1 == 6 { "O-O" } else { "O-O-O" });
+            } else {
+                let is_capture = board[to.0 as usize][to.1 as usize].is_some() || (piece == Piece::Pawn && from.1 != to.1);
+
+                if piece == Piece::Pawn {
+                    if is_capture {
+                        san.push((b'a' + from.1) as char);
+                    }
+                } else {
+                    san.push(Self::san_letter(&piece));
+
+                    // Name the origin file, else rank, else both, when another piece of the same kind can reach the same square
+                    let rivals: Vec<(u8, u8)> = Self::legal_moves_for_board(board, player, castling_rights, en_passant)?
+                        .into_iter()
+                        .filter(|legal_move| legal_move.to == to && legal_move.from != from)
+                        .filter(|legal_move| matches!(board[legal_move.from.0 as usize][legal_move.from.1 as usize], Some(ChessCell { piece: rival_piece, .. }) if rival_piece == piece))
+                        .map(|legal_move| legal_move.from)
+                        .collect();
+                    if !rivals.is_empty() {
+                        if rivals.iter().all(|rival| rival.1 != from.1) {
+                            san.push((b'a' + from.1) as char);
+                        } else if rivals.iter().all(|rival| rival.0 != from.0) {
+                            san.push((b'1' + from.0) as char);
+                        } else {
+                            san.push_str(&Self::square_to_algebraic(from));
+                        }
+                    }
+                }
+
+                if is_capture {
+                    san.push('x');
+                }
+                san.push_str(&Self::square_to_algebraic(to));
+
+                if piece == Piece::Pawn && (to.0 == 0 || to.0 == 7) {
+                    san.push('=');
+                    san.push(Self::san_letter(&chess_move.promotion.unwrap_or(Piece::Queen)));
+                }
+            }
+
+            // Play the move to tell whether it checks or mates the opponent
+            let mut board_after_move = *board;
+            let mut castling_rights_after_move = *castling_rights;
+            let mut en_passant_after_move = *en_passant;
+            Self::update_board_from_move(&mut board_after_move, player, chess_move)?;
+            Self::update_castling_rights(&mut castling_rights_after_move, chess_move);
+            Self::update_en_passant_target(&mut en_passant_after_move, &board_after_move, chess_move);
+            let opposing_player = Self::opposing_player(player);
+            if Self::is_check_mate(&board_after_move, &opposing_player, &castling_rights_after_move, &en_passant_after_move)? {
+                san.push('#');
+            } else if Self::is_in_check(&board_after_move, &opposing_player)? {
+                san.push('+');
+            }
+
+            Ok(san)
+        }
+
+        fn san_letter(piece: &Piece) -> char {
+            match piece {
+                Piece::Pawn => 'P',
+                Piece::Knight => 'N',
+                Piece::Bishop => 'B',
+                Piece::Rook => 'R',
+                Piece::Queen => 'Q',
+                Piece::King => 'K',
+            }
+        }
+
+        // UTC date of a millisecond timestamp as YYYY.MM.DD, unknown for sessions stored without a creation time.
+        // The day count is turned into a civil date with Howard Hinnant's civil_from_days algorithm.
+        pub fn pgn_date(timestamp: u64) -> String {
+            if timestamp == 0 {
+                return String::from("????.??.??");
+            }
+            let days = timestamp / 86_400_000 + 719_468; // Days since 0000-03-01
+            let era = days / 146_097;
+            let day_of_era = days % 146_097;
+            let year_of_era = (day_of_era - day_of_era / 1_460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+            let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+            let month_from_march = (5 * day_of_year + 2) / 153;
+            let day = day_of_year - (153 * month_from_march + 2) / 5 + 1;
+            let month = if month_from_march < 10 { month_from_march + 3 } else { month_from_march - 9 };
+            let year = era * 400 + year_of_era + if month <= 2 { 1 } else { 0 };
+            format!("{:04}.{:02}.{:02}", year, month, day)
+        }
+
+        pub fn game_state_lobby_to_pgn(game_state: &GameStateLobby) -> Result<String> {
+            let player_name = |address: Option<[u8; 32]>| -> Result<String> {
+                match address {
+                    Some(address) => Ok(String::from(Self::bytes_to_hex_string(address)?.as_str())),
+                    None => Ok(String::from("?")),
+                }
+            };
+            let result = match game_state.status {
+                GameStatus::Ongoing | GameStatus::Cancelled | GameStatus::Expired => "*",
+                GameStatus::WonByPlayerWhite => "1-0",
+                GameStatus::WonByPlayerBlack => "0-1",
+                GameStatus::Stalemate | GameStatus::Draw => "1/2-1/2",
+            };
+
+            // Seven Tag Roster, plus the starting position of games not started from the initial one
+            let mut pgn = format!(
+                "[Event \"Oh My Chess game\"]\n[Site \"Phala Network\"]\n[Date \"{}\"]\n[Round \"-\"]\n[White \"{}\"]\n[Black \"{}\"]\n[Result \"{}\"]\n",
+                Self::pgn_date(game_state.created_at),
+                player_name(game_state.players.white)?,
+                player_name(game_state.players.black)?,
+                result,
+            );
+            let (mut turn, mut fullmove_number) = (Player::White, 1);
+            if let Some(starting_fen) = &game_state.starting_fen {
+                pgn.push_str(&format!("[SetUp \"1\"]\n[FEN \"{}\"]\n", starting_fen.0));
+                let starting_position = Self::game_state_lobby_from_fen(starting_fen.0.as_str(), game_state.players.clone())?;
+                turn = starting_position.turn;
+                fullmove_number = starting_position.fullmove_number;
+            }
+            pgn.push('\n');
+
+            // Movetext, wrapped before 80 characters
+            let mut tokens: Vec<String> = Vec::new();
+            for (index, san) in game_state.moves.iter().enumerate() {
+                match turn {
+                    Player::White => tokens.push(format!("{}.", fullmove_number)),
+                    Player::Black if index == 0 => tokens.push(format!("{}...", fullmove_number)),
+                    Player::Black => {},
+                }
+                tokens.push(san.0.clone());
+                if turn == Player::Black {
+                    fullmove_number += 1;
+                }
+                turn = Self::opposing_player(&turn);
+            }
+            tokens.push(String::from(result));
+
+            let mut line_length = 0;
+            for token in tokens {
+                if line_length > 0 && line_length + 1 + token.len() > 79 {
+                    pgn.push('\n');
+                    line_length = 0;
+                } else if line_length > 0 {
+                    pgn.push(' ');
+                    line_length += 1;
+                }
+                line_length += token.len();
+                pgn.push_str(&token);
+            }
+            pgn.push('\n');
+
+            Ok(pgn)
+        }
+
+        pub fn square_to_algebraic(square: (u8, u8)) -> String {
+            let mut name = String::new();
+            name.push((b'a' + square.1) as char);
+            name.push((b'1' + square.0) as char);
+            name
+        }
+
+        pub fn square_from_algebraic(name: &str) -> Option<(u8, u8)> {
+            match name.as_bytes() {
+                [file @ b'a'..=b'h', rank @ b'1'..=b'8'] => Some((rank - b'1', file - b'a')),
+                _ => None,
+            }
+        }
+
+        pub fn check_move_boundaries(chess_move: &ChessMove) -> Result<()> {
+            if chess_move.from.0 > 7 || chess_move.from.1 > 7 {
+                return Err(OutOfBoardChessFrom);
+            }
+            if chess_move.to.0 > 7 || chess_move.to.1 > 7 {
+                return Err(OutOfBoardChessTo);
+            }
+            Ok(())
+        }
+
+        pub fn check_promotion_validity(board: &Board, chess_move: &ChessMove) -> Result<()> {
+            let is_promoting_pawn_move = matches!(board[chess_move.from.0 as usize][chess_move.from.1 as usize], Some(ChessCell { piece: Piece::Pawn, .. }))
+                && (chess_move.to.0 == 0 || chess_move.to.0 == 7);
+
+            match chess_move.promotion {
+                None => Ok(()), // Promoting pawn moves without a chosen piece default to a queen
+                Some(Piece::King) | Some(Piece::Pawn) => Err(NonValidPromotion),
+                Some(_) if is_promoting_pawn_move => Ok(()),
+                Some(_) => Err(NonValidPromotion),
+            }
+        }
+
+        pub fn check_move_validity_for_piece(board: &Board, player: &Player, castling_rights: &CastlingRights, en_passant: &Option<(u8, u8)>, chess_move: &ChessMove) -> Result<()> {
+            let ChessCell{ piece, .. } = match &board[chess_move.from.0 as usize][chess_move.from.1 as usize] {
+                Some(ref chess_cell) => chess_cell,
+                None => return Err(NoPieceBoardChessFrom), // No piece at source
+            };
+
+            match piece {
+                Piece::Pawn => Self::check_move_validity_pawn(board, player, en_passant, chess_move),
+                Piece::Knight => Self::check_move_validity_knight(chess_move),
+                Piece::Bishop => Self::check_move_validity_bishop(board, chess_move),
+                Piece::Rook => Self::check_move_validity_rook(board, chess_move),
+                Piece::Queen => Self::check_move_validity_queen(board, chess_move),
+                Piece::King => Self::check_move_validity_king(board, player, castling_rights, chess_move),
+            }
+        }
+
+        // Helper function to find the king's position for the given player
+        pub fn find_king_position(board: &Board, player: &Player) -> Result<(u8, u8)> {
+            for x in 0..8 {
+                for y in 0..8 {
+                    if let Some(ChessCell { piece: Piece::King, player: cell_player }) = board[x][y] {
+                        if cell_player == *player {
+                            return Ok((x as u8, y as u8));
+                        }
+                    }
+                }
+            }
+
+            Err(CouldNotFindKingPiece) // It should be impossible to not find the king
+        }
+
+        pub fn is_check_mate(board: &Board, player: &Player, castling_rights: &CastlingRights, en_passant: &Option<(u8, u8)>) -> Result<bool> {
+            // Checkmate: the player is in check and has no legal move at all, whether escaping with the king,
+            // capturing the attacker or interposing a piece
+            if !Self::is_in_check(board, player)? {
+                return Ok(false);
+            }
+            Ok(Self::legal_moves_for_board(board, player, castling_rights, en_passant)?.is_empty())
+        }
+
+        pub fn is_stalemate(board: &Board, player: &Player, castling_rights: &CastlingRights, en_passant: &Option<(u8, u8)>) -> Result<bool> {
+            // Stalemate: the player is not in check but has no legal move to play
+            if Self::is_in_check(board, player)? {
+                return Ok(false);
+            }
+            Ok(Self::legal_moves_for_board(board, player, castling_rights, en_passant)?.is_empty())
+        }
+
+        pub fn legal_moves_for_board(board: &Board, player: &Player, castling_rights: &CastlingRights, en_passant: &Option<(u8, u8)>) -> Result<Vec<ChessMove>> {
+            let mut legal_moves = Vec::new();
+
+            for fx in 0..8u8 {
+                for fy in 0..8u8 {
+                    let piece = match board[fx as usize][fy as usize] {
+                        Some(ChessCell { piece, player: cell_player }) if cell_player == *player => piece,
+                        _ => continue,
+                    };
+
+                    for tx in 0..8u8 {
+                        for ty in 0..8u8 {
+                            if let Some(ChessCell { player: cell_player, .. }) = board[tx as usize][ty as usize] {
+                                if cell_player == *player {
+                                    continue; // Cannot move onto one of our own pieces
+                                }
+                            }
+
+                            let chess_move = ChessMove { from: (fx, fy), to: (tx, ty), promotion: None };
+                            if Self::check_move_validity_for_piece(board, player, castling_rights, en_passant, &chess_move).is_err() {
+                                continue;
+                            }
+
+                            // Filter out moves leaving our own king in check
+                            if Self::check_if_move_puts_in_check(board, player, &chess_move).is_err() {
+                                continue;
+                            }
+
+                            if piece == Piece::Pawn && (tx == 0 || tx == 7) {
+                                for promotion in [Piece::Queen, Piece::Rook, Piece::Bishop, Piece::Knight] {
+                                    legal_moves.push(ChessMove { promotion: Some(promotion), ..chess_move.clone() });
+                                }
+                            } else {
+                                legal_moves.push(chess_move);
+                            }
+                        }
+                    }
+                }
+            }
+
+            Ok(legal_moves)
+        }
+
+        pub fn is_in_check(board: &Board, player: &Player) -> Result<bool> {
+            let king_position = Self::find_king_position(board, player)?;
+            Ok(Self::is_square_attacked_by(board, king_position, &Self::opposing_player(player)))
+        }
+
+        pub fn opposing_player(player: &Player) -> Player {
+            match player {
+                Player::Black => Player::White,
+                Player::White => Player::Black,
+            }
+        }
+
+        // Squares attacked by the given player, whether they are empty or occupied by any piece
+        pub fn attack_map(board: &Board, attacker: &Player) -> [[bool; 8]; 8] {
+            let mut attacked_squares = [[false; 8]; 8];
+            for (x, row) in attacked_squares.iter_mut().enumerate() {
+                for (y, attacked) in row.iter_mut().enumerate() {
+                    *attacked = Self::is_square_attacked_by(board, (x as u8, y as u8), attacker);
+                }
+            }
+            attacked_squares
+        }
+
+        pub fn is_square_attacked_by(board: &Board, square: (u8, u8), attacker: &Player) -> bool {
+            for (x, row) in board.iter().enumerate() {
+                for (y, cell) in row.iter().enumerate() {
+                    if let Some(chess_cell) = cell {
+                        if chess_cell.player == *attacker && Self::does_piece_attack_square(board, chess_cell, (x as u8, y as u8), square) {
+                            return true;
+                        }
+                    }
+                }
+            }
+            false
+        }
+
+        fn does_piece_attack_square(board: &Board, chess_cell: &ChessCell, from: (u8, u8), square: (u8, u8)) -> bool {
+            if from == square {
+                return false;
+            }
+
+            let attack_move = ChessMove { from, to: square, promotion: None };
+            match chess_cell.piece {
+                Piece::Pawn => {
+                    // Pawns only attack diagonally forward, forward being given by the pawn's own colour
+                    let forward = match chess_cell.player {
+                        Player::White => 1,
+                        Player::Black => -1,
+                    };
+                    from.0 as i32 + forward == square.0 as i32 && (from.1 as i32 - square.1 as i32).abs() == 1
+                },
+                Piece::Knight => Self::check_move_validity_knight(&attack_move).is_ok(),
+                Piece::Bishop => Self::check_move_validity_bishop(board, &attack_move).is_ok(),
+                Piece::Rook => Self::check_move_validity_rook(board, &attack_move).is_ok(),
+                Piece::Queen => Self::check_move_validity_queen(board, &attack_move).is_ok(),
+                // Castling never attacks, only the king's single steps do
+                Piece::King => (from.0 as i8 - square.0 as i8).abs() <= 1 && (from.1 as i8 - square.1 as i8).abs() <= 1,
+            }
+        }
+
+        pub fn check_move_validity_pawn(board: &Board, player: &Player, en_passant: &Option<(u8, u8)>, chess_move: &ChessMove) -> Result<()> {
+            let (fx, fy) = chess_move.from;
+            let (tx, ty) = chess_move.to;
+
+            let forward = match player {
+                Player::White => 1,
+                Player::Black => -1,
+            };
+
+            // Check forward move of 1 step
+            if fx as i32 + forward == tx as i32 && fy == ty && board[tx as usize][ty as usize].is_none() {
+                return Ok(());
+            }
+
+            // Check forward move of 2 steps
+            if ((player == &Player::White && fx == 1) || (player == &Player::Black && fx == 6))
+                && tx as i32 == fx as i32 + 2 * forward && fy == ty && board[tx as usize][ty as usize].is_none()
+                && board[(fx as i32 + forward) as usize][fy as usize].is_none() {
+                // Check if the path is clear
+                return Ok(());
+            }
+
+            // Check capture move
+            if fx as i32 + forward == tx as i32 && (fy as i32 - 1 == ty as i32 || fy as i32 + 1 == ty as i32) {
+                if let Some(ChessCell{player: piece_player, ..}) = board[tx as usize][ty as usize] {
+                    if *player != piece_player {
+                        // Capture if it's an opponent's piece
+                        return Ok(())
+                    }
+                }
+                if *en_passant == Some((tx, ty)) {
+                    // Capture en passant the pawn that has just skipped this square
+                    return Ok(())
+                }
+            }
+
+            Err(NonValidMove)
+        }
+
+        pub fn check_move_validity_knight(chess_move: &ChessMove) -> Result<()> {
+            let (fx, fy, tx, ty) = (chess_move.from.0, chess_move.from.1, chess_move.to.0, chess_move.to.1);
+            let dx = (fx as i32 - tx as i32).abs();
+            let dy = (fy as i32 - ty as i32).abs();
+
+            // Check L-shape move
+            if (dx == 2 && dy == 1) || (dx == 1 && dy == 2) { Ok(()) } else { Err(NonValidMove) }
+        }
+
+        pub fn check_move_validity_bishop(board: &Board, chess_move: &ChessMove) -> Result<()> {
+            // Bishop can move diagonally
+            let is_diagonal = (chess_move.from.0 as i32 - chess_move.to.0 as i32).abs() == (chess_move.from.1 as i32 - chess_move.to.1 as i32).abs();
+
+            if is_diagonal {
+                // Diagonal move: Ensure the path is clear
+                if Self::is_path_clear(board, chess_move) { Ok(()) }
+                else { Err(NonValidMove) }
+            } else { Err(NonValidMove) }
+        }
+
+        pub fn check_move_validity_rook(board: &Board, chess_move: &ChessMove) -> Result<()> {
+            // Rook can move horizontally or vertically
+            let is_horizontal = chess_move.from.0 == chess_move.to.0;
+            let is_vertical = chess_move.from.1 == chess_move.to.1;
+
+            if is_horizontal {
+                // Horizontal move: Ensure the path is clear
+                if Self::is_path_clear(board, chess_move) { Ok(()) }
+                else { Err(NonValidMove) }
+            } else if is_vertical {
+                // Vertical move: Ensure the path is clear
+                if Self::is_path_clear(board, chess_move) { Ok(()) }
+                else { Err(NonValidMove) }
+            } else {
+                Err(NonValidMove)
+            }
+        }
+
+        pub fn check_move_validity_king(board: &Board, player: &Player, castling_rights: &CastlingRights, chess_move: &ChessMove) -> Result<()> {
+            // Calculate the difference in the move for both axes
+            let delta_row = (chess_move.from.0 as i8 - chess_move.to.0 as i8).abs();
+            let delta_col = (chess_move.from.1 as i8 - chess_move.to.1 as i8).abs();
+
+            if delta_row <= 1 && delta_col <= 1 { Ok(()) }
+            else if delta_row == 0 && delta_col == 2 { Self::check_castling_validity(board, player, castling_rights, chess_move) }
+            else { Err(NonValidMove) }
+        }
+
+        fn check_castling_validity(board: &Board, player: &Player, castling_rights: &CastlingRights, chess_move: &ChessMove) -> Result<()> {
+            let home_rank = match player {
+                Player::White => 0,
+                Player::Black => 7,
+            };
+            if chess_move.from != (home_rank, 4) || chess_move.to.0 != home_rank {
+                return Err(NonValidMove);
+            }
+
+            let (rook_y, has_castling_right) = match (player, chess_move.to.1) {
+                (Player::White, 6) => (7, castling_rights.white_king_side),
+                (Player::White, 2) => (0, castling_rights.white_queen_side),
+                (Player::Black, 6) => (7, castling_rights.black_king_side),
+                (Player::Black, 2) => (0, castling_rights.black_queen_side),
+                _ => return Err(NonValidMove),
+            };
+            if !has_castling_right || board[home_rank as usize][rook_y as usize] != Some(ChessCell { piece: Piece::Rook, player: *player }) {
+                return Err(NonValidMove);
+            }
+
+            // Every square between the king and the rook must be empty
+            let rook_to_king_move = ChessMove { from: (home_rank, rook_y), to: chess_move.from, promotion: None };
+            if !Self::is_path_clear(board, &rook_to_king_move) {
+                return Err(NonValidMove);
+            }
+
+            // The king cannot castle out of check, through an attacked square nor into check
+            let opposing_player = Self::opposing_player(player);
+            let crossed_square = (home_rank, (chess_move.from.1 + chess_move.to.1) / 2);
+            for square in [chess_move.from, crossed_square, chess_move.to] {
+                if Self::is_square_attacked_by(board, square, &opposing_player) {
+                    return Err(NonValidMove);
+                }
+            }
+
+            Ok(())
+        }
+
+        pub fn check_move_validity_queen(board: &Board, chess_move: &ChessMove) -> Result<()> {
+            // Queen can move horizontally, vertically, or diagonally
+            let from = chess_move.from;
+            let to = chess_move.to;
+            let is_horizontal = from.0 == to.0;
+            let is_vertical = from.1 == to.1;
+            let is_diagonal = (from.0 as i32 - to.0 as i32).abs() == (from.1 as i32 - to.1 as i32).abs();
+
+            if is_horizontal {
+                if Self::is_path_clear(board, chess_move) { Ok(()) }
+                else { Err(NonValidMove) }
+            } else if is_vertical {
+                if Self::is_path_clear(board, chess_move) { Ok(()) }
+                else { Err(NonValidMove) }
+            } else if is_diagonal {
+                if Self::is_path_clear(board, chess_move) { Ok(()) }
+                else { Err(NonValidMove) }
+            } else {
+                Err(NonValidMove)
+            }
+        }
+
+        fn is_path_clear(board: &[[Option<ChessCell>; 8]; 8], chess_move: &ChessMove) -> bool {
+            let from = (chess_move.from.0 as i16, chess_move.from.1 as i16);
+            let to = (chess_move.to.0 as i16, chess_move.to.1 as i16);
+            let (dx, dy) = (to.0 as i32 - from.0 as i32, to.1 as i32 - from.1 as i32);
+            let step_x = dx.signum() as i16;
+            let step_y = dy.signum() as i16;
+
+            let mut current_x = from.0;
+            let mut current_y = from.1;
+
+            while (current_x, current_y) != (to.0, to.1) {
+                current_x += step_x;
+                current_y += step_y;
+
+                // Avoid checking the destination square for a piece
+                if (current_x, current_y) == (to.0, to.1) {
+                    break;
+                }
+
+                // Check if the path is clear
+                if board[current_x as usize][current_y as usize].is_some() {
+                    return false;
+                }
+            }
+            true
+        }
+    }
+
+    // Persistence of the game sessions, the sessions tracked for each player and the ratings.
+    // The game logic only goes through this trait, each storage backend being an adapter of it.
+    pub trait GameStore {
+        // Returns the id of the new session
+        fn insert_game_session(&self, game_state: &GameStateLobby) -> Result<String>;
+        fn find_game_session(&self, session_id: &str) -> Result<GameStateLobby>;
+        // Both updates only apply while the stored session is still at expected_version, and fail with
+        // ConcurrentModification once another update got in first. The game state carries its new version,
+        // a status update bumps the version itself.
+        fn update_game_session(&self, session_id: &str, expected_version: u32, game_state: &GameState) -> Result<()>;
+        fn update_game_session_status(&self, session_id: &str, expected_version: u32, status: &GameStatus) -> Result<()>;
+        // Lobbies matching the query, oldest first
+        fn find_lobbies(&self, query: &LobbyQuery, skip: u32, limit: u32) -> Result<Vec<LobbyDocument>>;
+        fn track_session(&self, player_address: [u8; 32], session_id: &str) -> Result<()>;
+        fn untrack_session(&self, player_address: [u8; 32], session_id: &str) -> Result<()>;
+        fn find_tracked_sessions(&self, player_address: [u8; 32]) -> Result<Vec<String>>;
+        // Players who never finished a rated game get the default rating
+        fn find_player_rating(&self, player_address: [u8; 32]) -> Result<PlayerRating>;
+        // Only applies to the rating at the expected version, the rating carries its next version
+        fn update_player_rating(&self, player_address: [u8; 32], expected_version: u32, player_rating: &PlayerRating) -> Result<()>;
+    }
+
+    // Where the contract keeps its data, selected by the admin
+    #[derive(Encode, Decode, Clone, Copy, Debug, PartialEq, TypeInfo)]
+    #[cfg_attr(feature = "std", derive(ink::storage::traits::StorageLayout))]
+    pub enum StorageBackend {
+        // A MongoDB Atlas cluster, through its Data API
+        MongoDB,
+        // The local cache of the worker running the contract, lost when the worker restarts or evicts it
+        LocalCache,
+    }
+
+    #[derive(Clone, Debug)]
+    pub enum LobbyQuery {
+        // Lobbies with exactly one seat taken, still young enough to be joined
+        Open { filters: LobbyFilters, created_after: u64, excluded_player: Option<[u8; 32]> },
+        // Lobbies still waiting for a second player, created before the timestamp
+        Stale { created_before: u64 },
+    }
+
+    impl LobbyQuery {
+        // Same selection as MongoDBStore::lobbies_filter, for the adapters without a query language
+        fn matches(&self, lobby: &GameStateLobby) -> bool {
+            let (white, black) = (lobby.players.white, lobby.players.black);
+            if lobby.status != GameStatus::Ongoing {
+                return false;
+            }
+
+            match self {
+                LobbyQuery::Open { filters, created_after, excluded_player } => {
+                    // The free seat of a random colour lobby says nothing about the colour its joiner will get
+                    let seat_matches = match filters.colour {
+                        Some(Player::White) => white.is_none() && black.is_some() && lobby.colour_draw.is_none(),
+                        Some(Player::Black) => black.is_none() && white.is_some() && lobby.colour_draw.is_none(),
+                        None => white.is_none() != black.is_none(),
+                    };
+                    let rated_matches = filters.rated.is_none() || filters.rated == Some(lobby.rated);
+                    let time_control_matches = filters.time_control.is_none() || filters.time_control == lobby.clock.map(|clock| clock.time_control);
+                    // A player cannot be matched against their own lobby
+                    let is_excluded = excluded_player.is_some() && (white == *excluded_player || black == *excluded_player);
+
+                    lobby.created_at >= *created_after && seat_matches && rated_matches && time_control_matches && !is_excluded
+                },
+                LobbyQuery::Stale { created_before } => lobby.created_at < *created_before && (white.is_none() || black.is_none()),
+            }
+        }
+
+        // Lobbies among all the sessions matching the query, oldest first
+        fn select(&self, game_sessions: Vec<(String, GameStateLobby)>, skip: u32, limit: u32) -> Vec<LobbyDocument> {
+            let mut lobbies: Vec<(String, GameStateLobby)> = game_sessions.into_iter()
+                .filter(|(_, game_state_lobby)| self.matches(game_state_lobby))
+                .collect();
+            lobbies.sort_by_key(|(_, game_state_lobby)| game_state_lobby.created_at);
+
+            lobbies.into_iter()
+                .skip(skip as usize)
+                .take(limit as usize)
+                .map(|(session_id, game_state_lobby)| LobbyDocument {
+                    id: JsonString(session_id),
+                    players: game_state_lobby.players,
+                    colour_draw: game_state_lobby.colour_draw,
+                    clock: game_state_lobby.clock,
+                    rated: game_state_lobby.rated,
+                    created_at: game_state_lobby.created_at,
+                    version: game_state_lobby.version,
+                })
+                .collect()
+        }
+    }
+
+    // Adapter for the MongoDB Atlas Data API
+    pub struct MongoDBStore<'a> {
+        url: &'a str,
+        api_key: &'a str,
+        data_source: &'a str,
+        database: &'a str,
+        game_sessions_collection: &'a str,
+        players_sessions_collection: &'a str,
+        player_ratings_collection: &'a str,
+    }
+
+    impl MongoDBStore<'_> {
+        // Where a request body points the Data API to
+        fn namespace(&self, collection: &str) -> String {
+            format!(r#""collection":"{}",
+                "database":"{}",
+                "dataSource":"{}""#, collection, self.database, self.data_source)
+        }
+
+        fn post(&self, action: &str, data: String) -> HttpResponse {
+            let method = String::from("POST"); // HTTP Method for the request
+            let url = format!("{}/action/{}", self.url, action);
+
+            // Prepare headers
+            let headers = alloc::vec![
+                (String::from("Content-Type"), String::from("application/json")),
+                (String::from("Access-Control-Request-Headers"), String::from("*")),
+                (String::from("api-key"), String::from(self.api_key)),
+            ];
+
+            pink::http_req!(
+                method,
+                url,
+                data.into_bytes(),
+                headers
+            )
+        }
+
+        // The filter of a conditional update matches nothing once the version has moved on
+        // Documents written before sessions were versioned have no version, they are read at version 0
+        fn version_filter(expected_version: u32) -> String {
+            if expected_version == 0 { String::from(r#"{"$in": [0, null]}"#) }
+            else { format!("{}", expected_version) }
+        }
+
+        fn check_conditional_update(response: &HttpResponse) -> Result<()> {
+            if response.status_code != 200 && response.status_code != 201 {
+                // An upsert racing with the insertion of the same document
+                let duplicate_key = String::from_utf8_lossy(&response.body).contains("duplicate key");
+                return Err(if duplicate_key { ConcurrentModification } else { CouldNotUpdateDB });
+            }
+
+            let updated_document_mongo = serde_json_core::from_slice::<UpdatedMongoDBDocumentResult>(response.body.as_slice())
+                .map_err(|_| { CouldNotUpdateDB })
+                .map(|(updated_document_mongo, _)| { updated_document_mongo })?;
+
+            if updated_document_mongo.matchedCount == 0 && updated_document_mongo.upsertedId.is_none() { Err(ConcurrentModification) }
+            else { Ok(()) }
+        }
+
+        fn lobbies_filter(query: &LobbyQuery) -> Result<String> {
+            let (filters, created_after, excluded_player) = match query {
+                LobbyQuery::Open { filters, created_after, excluded_player } => (filters, created_after, excluded_player),
+                // Lobbies stored before they had a creation time are the oldest of all
+                LobbyQuery::Stale { created_before } => {
+                    return Ok(format!(r#"{{"status": "Ongoing", "$and": [{{"$or": [{{"created_at": {{"$lt": {}}}}}, {{"created_at": {{"$exists": false}}}}]}}, {{"$or": [{{"players.white": null}}, {{"players.black": null}}]}}]}}"#, created_before));
+                },
+            };
+            let mut filter = format!(r#"{{"status": "Ongoing", "created_at": {{"$gte": {}}}"#, created_after);
+
+            // The free seat of a random colour lobby says nothing about the colour its joiner will get
+            filter.push_str(match filters.colour {
+                Some(Player::White) => r#", "players.white": null, "players.black": {"$ne": null}, "colour_draw": null"#,
+                Some(Player::Black) => r#", "players.black": null, "players.white": {"$ne": null}, "colour_draw": null"#,
+                None => r#", "$or": [{"players.white": null, "players.black": {"$ne": null}}, {"players.black": null, "players.white": {"$ne": null}}]"#,
+            });
+            if let Some(rated) = filters.rated {
+                filter.push_str(&format!(r#", "rated": {}"#, rated));
+            }
+            if let Some(time_control) = &(filters.time_control) {
+                let json_time_control = serde_json::to_string(time_control).map_err(|_| { ErrorFetchingFromDB })?;
+                filter.push_str(&format!(r#", "clock.time_control": {}"#, json_time_control));
+            }
+            if let Some(excluded_player) = excluded_player {
+                // A player cannot be matched against their own lobby
+                let json_address = serde_json::to_string(excluded_player).map_err(|_| { ErrorFetchingFromDB })?;
+                filter.push_str(&format!(r#", "$nor": [{{"players.white": {}}}, {{"players.black": {}}}]"#, json_address, json_address));
+            }
+            filter.push('}');
+
+            Ok(filter)
+        }
+    }
+
+    impl GameStore for MongoDBStore<'_> {
+        fn insert_game_session(&self, game_state: &GameStateLobby) -> Result<String> {
+            let json_game_state = serde_json::to_string(game_state).map_err(|_| { ErrorInsertingToDB })?;
+
+            let data = format!(r#"{{
+                {},
+                "document":{}
+            }}"#, self.namespace(self.game_sessions_collection), json_game_state);
+
+            let response = self.post("insertOne", data);
+
+            serde_json_core::from_slice::<InsertedMongoDBDocumentResult>(response.body.as_slice())
+                .map_err(|_| { ErrorInsertingToDB })
+                .map(|(inserted_document_mongo, _)| { inserted_document_mongo.insertedId })?
+                .map(|inserted_id| { inserted_id.0 })
+                .ok_or(ErrorInsertingToDB)
+        }
+
+        fn find_game_session(&self, session_id: &str) -> Result<GameStateLobby> {
+            let data = format!(r#"{{
+                {},
+                "filter": {{"_id": {{"$oid": "{}"}}}},
+                "projection":{{"_id":0,"turn":1,"status":1,"created_at":1,"draw_offer":1,"takeback_request":1,"clock":1,"rated":1,"version":1,"ratings_update":1,"players":1,"colour_draw":1,"board":1,"castling_rights":1,"en_passant":1,"halfmove_clock":1,"fullmove_number":1,"moves":1,"starting_fen":1,"position_history":1,"undo_history":1}}
+            }}"#, self.namespace(self.game_sessions_collection), session_id);
+
+            let response = self.post("findOne", data);
+
+            serde_json_core::from_slice::<FindMongoDBDocumentResult>(response.body.as_slice())
+                .map_err(|_| { ErrorFetchingFromDB })
+                .map(|(mongodb, _)| { mongodb.document })?
+                .ok_or(NoElementFoundInDB)
+        }
+
+        fn update_game_session(&self, session_id: &str, expected_version: u32, game_state: &GameState) -> Result<()> {
+            let json_game_state = serde_json::to_string(game_state).map_err(|_| { CouldNotUpdateDB })?;
+
+            let data = format!(r#"{{
+                {},
+                "filter": {{"_id": {{"$oid": "{}"}}, "version": {}}},
+                "update":{{"$set": {}}}
+            }}"#, self.namespace(self.game_sessions_collection), session_id, Self::version_filter(expected_version), json_game_state);
+
+            let response = self.post("updateOne", data);
+
+            Self::check_conditional_update(&response)
+        }
+
+        fn update_game_session_status(&self, session_id: &str, expected_version: u32, status: &GameStatus) -> Result<()> {
+            let json_status = serde_json::to_string(status).map_err(|_| { CouldNotUpdateDB })?;
+
+            let data = format!(r#"{{
+                {},
+                "filter": {{"_id": {{"$oid": "{}"}}, "version": {}}},
+                "update":{{"$set": {{"status": {}, "version": {}}}}}
+            }}"#, self.namespace(self.game_sessions_collection), session_id, Self::version_filter(expected_version), json_status, expected_version + 1);
+
+            let response = self.post("updateOne", data);
+
+            Self::check_conditional_update(&response)
+        }
+
+        fn find_lobbies(&self, query: &LobbyQuery, skip: u32, limit: u32) -> Result<Vec<LobbyDocument>> {
+            let data = format!(r#"{{
+                {},
+                "filter": {},
+                "projection":{{"_id":1,"players":1,"colour_draw":1,"clock":1,"rated":1,"created_at":1,"version":1}},
+                "sort":{{"created_at":1}},
+                "skip":{},
+                "limit":{}
+            }}"#, self.namespace(self.game_sessions_collection), Self::lobbies_filter(query)?, skip, limit);
+
+            let response = self.post("find", data);
+
+            if response.status_code != 200 {
+                return Err(ErrorFetchingFromDB);
+            }
+
+            serde_json_core::from_slice::<FindMongoDBLobbiesResult>(response.body.as_slice())
+                .map_err(|_| { ErrorFetchingFromDB })
+                .map(|(lobbies, _)| { lobbies.documents })
+        }
+
+        fn track_session(&self, player_address: [u8; 32], session_id: &str) -> Result<()> {
+            let player_address_hex_string = OhMyChess::bytes_to_hex_string(player_address)?;
+
+            let data = format!(r#"{{
+                {},
+                "filter":{{ "_id": "{}" }},
+                "update":{{"$push": {{"sessions": "{}"}} }},
+                "upsert":true
+            }}"#, self.namespace(self.players_sessions_collection), player_address_hex_string, session_id);
+
+            let response = self.post("updateOne", data);
+
+            if response.status_code == 200 || response.status_code == 201 { Ok(()) }
+            else { Err(CouldNotUpdateDB) }
+        }
+
+        fn untrack_session(&self, player_address: [u8; 32], session_id: &str) -> Result<()> {
+            let player_address_hex_string = OhMyChess::bytes_to_hex_string(player_address)?;
+
+            let data = format!(r#"{{
+                {},
+                "filter":{{ "_id": "{}" }},
+                "update":{{"$pull": {{"sessions": "{}"}} }}
+            }}"#, self.namespace(self.players_sessions_collection), player_address_hex_string, session_id);
+
+            let response = self.post("updateOne", data);
+
+            if response.status_code == 200 { Ok(()) }
+            else { Err(CouldNotUpdateDB) }
+        }
+
+        fn find_tracked_sessions(&self, player_address: [u8; 32]) -> Result<Vec<String>> {
+            let player_address_hex_string = OhMyChess::bytes_to_hex_string(player_address)?;
+
+            let data = format!(r#"{{
+                {},
+                "filter":{{"_id": "{}"}},
+                "projection":{{"sessions": 1, "_id": 0}}
+            }}"#, self.namespace(self.players_sessions_collection), player_address_hex_string);
+
+            let response = self.post("findOne", data);
+
+            if response.status_code != 200 {
+                return Err(ErrorFetchingFromDB);
+            }
+
+            // A player who never joined a session has no tracker yet
+            serde_json_core::from_slice::<FindMongoDBTrackDocumentResult>(&response.body)
+                .map_err(|_| { ErrorFetchingFromDB })
+                .map(|(mongodb, _)| { mongodb.document })
+                .map(|document| match document {
+                    Some(document) => document.sessions.into_iter().map(|session| session.0).collect(),
+                    None => Vec::new(),
+                })
+        }
+
+        fn find_player_rating(&self, player_address: [u8; 32]) -> Result<PlayerRating> {
+            let player_address_hex_string = OhMyChess::bytes_to_hex_string(player_address)?;
+
+            let data = format!(r#"{{
+                {},
+                "filter":{{"_id": "{}"}},
+                "projection":{{"_id":0,"rating":1,"deviation":1,"volatility":1,"games":1,"version":1,"last_session":1}}
+            }}"#, self.namespace(self.player_ratings_collection), player_address_hex_string);
+
+            let response = self.post("findOne", data);
+
+            if response.status_code != 200 {
+                return Err(ErrorFetchingFromDB);
+            }
+
+            serde_json_core::from_slice::<FindMongoDBRatingDocumentResult>(response.body.as_slice())
+                .map_err(|_| { ErrorFetchingFromDB })
+                .map(|(mongodb, _)| { mongodb.document.unwrap_or_default() })
+        }
+
+        fn update_player_rating(&self, player_address: [u8; 32], expected_version: u32, player_rating: &PlayerRating) -> Result<()> {
+            let player_address_hex_string = OhMyChess::bytes_to_hex_string(player_address)?;
+
+            let json_player_rating = serde_json::to_string(player_rating).map_err(|_| { CouldNotUpdateDB })?;
+
+            // The first rating of a player is inserted, as no document matches its version 0
+            let data = format!(r#"{{
+                {},
+                "filter":{{"_id": "{}", "version": {}}},
+                "update":{{"$set": {}}},
+                "upsert":true
+            }}"#, self.namespace(self.player_ratings_collection), player_address_hex_string, expected_version, json_player_rating);
+
+            let response = self.post("updateOne", data);
+
+            Self::check_conditional_update(&response)
+        }
+    }
+
+    // Adapter keeping everything in the local cache of the worker, SCALE encoded under "database/collection/id" keys.
+    // Nothing leaves the worker, but nothing outlives it either: fits casual games and trying the contract out.
+    pub struct LocalCacheStore<'a> {
+        database: &'a str,
+        game_sessions_collection: &'a str,
+        players_sessions_collection: &'a str,
+        player_ratings_collection: &'a str,
+    }
+
+    impl LocalCacheStore<'_> {
+        fn key(&self, collection: &str, id: &str) -> String {
+            format!("{}/{}/{}", self.database, collection, id)
+        }
+
+        // Ids of all the game sessions, the cache cannot be scanned
+        fn game_sessions_index_key(&self) -> String {
+            format!("{}/{}", self.database, self.game_sessions_collection)
+        }
+
+        fn get<T: Decode>(key: &str) -> Result<Option<T>> {
+            match pink::ext().cache_get(key.as_bytes()) {
+                Some(value) => T::decode(&mut value.as_slice()).map(Some).map_err(|_| { ErrorFetchingFromDB }),
+                None => Ok(None),
+            }
+        }
+
+        fn set<T: Encode>(key: &str, value: &T) -> Result<()> {
+            pink::ext().cache_set(key.as_bytes(), &value.encode()).map_err(|_| { CouldNotUpdateDB })
+        }
+
+        fn player_key(&self, collection: &str, player_address: [u8; 32]) -> Result<String> {
+            Ok(self.key(collection, &OhMyChess::bytes_to_hex_string(player_address)?))
+        }
+    }
+
+    // The cache has no compare-and-set: versions are checked right before writing, within the same query
+    impl GameStore for LocalCacheStore<'_> {
+        fn insert_game_session(&self, game_state: &GameStateLobby) -> Result<String> {
+            let index_key = self.game_sessions_index_key();
+            let mut session_ids: Vec<String> = Self::get(&index_key)?.unwrap_or_default();
+            // Shaped like the ObjectIds generated by MongoDB, skipping any id still taken after an eviction of the index
+            let mut session_number = session_ids.len() + 1;
+            while Self::get::<GameStateLobby>(&self.key(self.game_sessions_collection, &format!("{:024x}", session_number)))?.is_some() {
+                session_number += 1;
+            }
+            let session_id = format!("{:024x}", session_number);
+
+            Self::set(&self.key(self.game_sessions_collection, &session_id), game_state).map_err(|_| { ErrorInsertingToDB })?;
+            session_ids.push(session_id.clone());
+            Self::set(&index_key, &session_ids).map_err(|_| { ErrorInsertingToDB })?;
+            Ok(session_id)
+        }
+
+        fn find_game_session(&self, session_id: &str) -> Result<GameStateLobby> {
+            Self::get(&self.key(self.game_sessions_collection, session_id))?.ok_or(NoElementFoundInDB)
+        }
+
+        fn update_game_session(&self, session_id: &str, expected_version: u32, game_state: &GameState) -> Result<()> {
+            let game_state_lobby = self.find_game_session(session_id).map_err(|_| { CouldNotUpdateDB })?;
+            if game_state_lobby.version != expected_version {
+                return Err(ConcurrentModification);
+            }
+            Self::set(&self.key(self.game_sessions_collection, session_id), &OhMyChess::lobby_from_game_state(game_state.clone()))
+        }
+
+        fn update_game_session_status(&self, session_id: &str, expected_version: u32, status: &GameStatus) -> Result<()> {
+            let mut game_state_lobby = self.find_game_session(session_id).map_err(|_| { CouldNotUpdateDB })?;
+            if game_state_lobby.version != expected_version {
+                return Err(ConcurrentModification);
+            }
+            game_state_lobby.status = status.clone();
+            game_state_lobby.version = expected_version + 1;
+            Self::set(&self.key(self.game_sessions_collection, session_id), &game_state_lobby)
+        }
+
+        fn find_lobbies(&self, query: &LobbyQuery, skip: u32, limit: u32) -> Result<Vec<LobbyDocument>> {
+            let session_ids: Vec<String> = Self::get(&self.game_sessions_index_key())?.unwrap_or_default();
+            let mut game_sessions = Vec::new();
+            for session_id in session_ids {
+                // Sessions evicted from the cache are gone
+                if let Some(game_state_lobby) = Self::get(&self.key(self.game_sessions_collection, &session_id))? {
+                    game_sessions.push((session_id, game_state_lobby));
+                }
+            }
+            Ok(query.select(game_sessions, skip, limit))
+        }
+
+        fn track_session(&self, player_address: [u8; 32], session_id: &str) -> Result<()> {
+            let key = self.player_key(self.players_sessions_collection, player_address)?;
+            let mut sessions: Vec<String> = Self::get(&key)?.unwrap_or_default();
+            sessions.push(String::from(session_id));
+            Self::set(&key, &sessions)
+        }
+
+        fn untrack_session(&self, player_address: [u8; 32], session_id: &str) -> Result<()> {
+            let key = self.player_key(self.players_sessions_collection, player_address)?;
+            let mut sessions: Vec<String> = Self::get(&key)?.unwrap_or_default();
+            sessions.retain(|session| session != session_id);
+            Self::set(&key, &sessions)
+        }
+
+        fn find_tracked_sessions(&self, player_address: [u8; 32]) -> Result<Vec<String>> {
+            Ok(Self::get(&self.player_key(self.players_sessions_collection, player_address)?)?.unwrap_or_default())
+        }
+
+        fn find_player_rating(&self, player_address: [u8; 32]) -> Result<PlayerRating> {
+            Ok(Self::get(&self.player_key(self.player_ratings_collection, player_address)?)?.unwrap_or_default())
+        }
+
+        fn update_player_rating(&self, player_address: [u8; 32], expected_version: u32, player_rating: &PlayerRating) -> Result<()> {
+            if self.find_player_rating(player_address)?.version != expected_version {
+                return Err(ConcurrentModification);
+            }
+            Self::set(&self.player_key(self.player_ratings_collection, player_address)?, player_rating)
+        }
+    }
+
+    // Adapter keeping everything in memory, to unit test the game logic without any database
+    #[cfg(test)]
+    #[derive(Default)]
+    pub struct InMemoryStore {
+        game_sessions: RefCell<BTreeMap<String, GameStateLobby>>,
+        players_sessions: RefCell<BTreeMap<[u8; 32], Vec<String>>>,
+        player_ratings: RefCell<BTreeMap<[u8; 32], PlayerRating>>,
+    }
+
+    #[cfg(test)]
+    impl InMemoryStore {
+        pub fn new() -> Self {
+            Self::default()
+        }
+    }
+
+    #[cfg(test)]
+    impl GameStore for InMemoryStore {
+        fn insert_game_session(&self, game_state: &GameStateLobby) -> Result<String> {
+            let mut game_sessions = self.game_sessions.borrow_mut();
+            // Shaped like the ObjectIds generated by MongoDB
+            let session_id = format!("{:024x}", game_sessions.len() + 1);
+            game_sessions.insert(session_id.clone(), game_state.clone());
+            Ok(session_id)
+        }
+
+        fn find_game_session(&self, session_id: &str) -> Result<GameStateLobby> {
+            self.game_sessions.borrow().get(session_id).cloned().ok_or(NoElementFoundInDB)
+        }
+
+        fn update_game_session(&self, session_id: &str, expected_version: u32, game_state: &GameState) -> Result<()> {
+            let mut game_sessions = self.game_sessions.borrow_mut();
+            let game_state_lobby = game_sessions.get_mut(session_id).ok_or(CouldNotUpdateDB)?;
+            if game_state_lobby.version != expected_version {
+                return Err(ConcurrentModification);
+            }
+            *game_state_lobby = OhMyChess::lobby_from_game_state(game_state.clone());
+            Ok(())
+        }
+
+        fn update_game_session_status(&self, session_id: &str, expected_version: u32, status: &GameStatus) -> Result<()> {
+            let mut game_sessions = self.game_sessions.borrow_mut();
+            let game_state_lobby = game_sessions.get_mut(session_id).ok_or(CouldNotUpdateDB)?;
+            if game_state_lobby.version != expected_version {
+                return Err(ConcurrentModification);
+            }
+            game_state_lobby.status = status.clone();
+            game_state_lobby.version = expected_version + 1;
+            Ok(())
+        }
+
+        fn find_lobbies(&self, query: &LobbyQuery, skip: u32, limit: u32) -> Result<Vec<LobbyDocument>> {
+            let game_sessions = self.game_sessions.borrow().iter()
+                .map(|(session_id, game_state_lobby)| (session_id.clone(), game_state_lobby.clone()))
+                .collect();
+            Ok(query.select(game_sessions, skip, limit))
+        }
+
+        fn track_session(&self, player_address: [u8; 32], session_id: &str) -> Result<()> {
+            self.players_sessions.borrow_mut().entry(player_address).or_default().push(String::from(session_id));
+            Ok(())
+        }
+
+        fn untrack_session(&self, player_address: [u8; 32], session_id: &str) -> Result<()> {
+            if let Some(sessions) = self.players_sessions.borrow_mut().get_mut(&player_address) {
+                sessions.retain(|session| session != session_id);
+            }
+            Ok(())
+        }
+
+        fn find_tracked_sessions(&self, player_address: [u8; 32]) -> Result<Vec<String>> {
+            Ok(self.players_sessions.borrow().get(&player_address).cloned().unwrap_or_default())
+        }
+
+        fn find_player_rating(&self, player_address: [u8; 32]) -> Result<PlayerRating> {
+            Ok(self.player_ratings.borrow().get(&player_address).cloned().unwrap_or_default())
+        }
+
+        fn update_player_rating(&self, player_address: [u8; 32], expected_version: u32, player_rating: &PlayerRating) -> Result<()> {
+            let mut player_ratings = self.player_ratings.borrow_mut();
+            if player_ratings.get(&player_address).map_or(0, |player_rating| player_rating.version) != expected_version {
+                return Err(ConcurrentModification);
+            }
+            player_ratings.insert(player_address, player_rating.clone());
+            Ok(())
+        }
+    }
+
+
+    #[derive(Encode, Decode, Deserialize, Serialize, Clone, Debug, PartialEq, TypeInfo)]
+    pub enum Direction {
+        Horizontal,
+        Vertical,
+        Diagonal,
+    }
+
+    #[derive(Encode, Decode, Deserialize, Serialize, Copy, Clone, Debug, PartialEq, TypeInfo)]
+    pub enum Piece {
+        Pawn, Knight, Bishop, Rook, Queen, King
+    }
+
+    #[derive(Encode, Decode, Deserialize, Serialize, Copy, Clone, Debug, PartialEq, TypeInfo)]
+    pub enum Player {
+        Black, White
+    }
+
+    #[derive(Encode, Decode, Deserialize, Serialize, Clone, Debug, PartialEq, TypeInfo)]
+    pub struct PlayersAddresses {
+        black: [u8; 32],
+        white: [u8; 32],
+    }
+
+    #[derive(Encode, Decode, Deserialize, Serialize, Clone, Debug, PartialEq, TypeInfo)]
+    pub struct PlayersAddressesLobby {
+        black: Option<[u8; 32]>,
+        white: Option<[u8; 32]>,
+    }
+
+    #[derive(Encode, Decode, Deserialize, Serialize, Clone, Debug, PartialEq, TypeInfo)]
+    pub enum GameStatus {
+        Ongoing, WonByPlayerBlack, WonByPlayerWhite, Stalemate, Draw, Cancelled, Expired
+    }
+
+    pub type Board = [[Option<ChessCell>; 8]; 8];
+
+    const MILLISECONDS_PER_DAY: u64 = 24 * 60 * 60 * 1000;
+    const DEFAULT_LOBBY_TTL: u64 = 7 * MILLISECONDS_PER_DAY;
+    const STALE_LOBBIES_BATCH_SIZE: u32 = 20;
+    const OPEN_LOBBIES_PAGE_SIZE: u32 = 10;
+
+    // Fixed point numbers, FIXED_ONE standing for 1.0
+    pub type Fixed = i128;
+    const FIXED_ONE: Fixed = 1_000_000_000;
+    const LN_2: Fixed = 693_147_181;
+    const PI_SQUARED: Fixed = 9_869_604_401;
+
+    // Glicko-2 constants: the ratio between the Glicko and Glicko-2 scales, the system constant tau
+    // constraining volatility changes, and the convergence tolerance of the volatility computation
+    const GLICKO2_SCALE: Fixed = 173_717_800_000;
+    const GLICKO2_TAU: Fixed = 500_000_000;
+    const GLICKO2_EPSILON: Fixed = 1_000;
+    const GLICKO2_MAX_ITERATIONS: u32 = 100;
+    const GLICKO2_MIN_EXPECTED_SCORE: Fixed = 1_000;
+
+    fn first_fullmove_number() -> u16 {
+        1
+    }
+
+    #[derive(Encode, Decode, Deserialize, Serialize, Copy, Clone, Debug, Default, PartialEq, TypeInfo)]
+    pub struct CastlingRights {
+        white_king_side: bool,
+        white_queen_side: bool,
+        black_king_side: bool,
+        black_queen_side: bool,
+    }
+
+    // Fields added since the first release default when reading older documents, that stay playable
+    #[derive(Encode, Decode, Deserialize, Serialize, Clone, Debug, TypeInfo)]
+    pub struct GameState {
+        board: Board,
+        turn: Player,
+        players: PlayersAddresses,
+        #[serde(default)]
+        colour_draw: Option<ColourDraw>,
+        status: GameStatus,
+        #[serde(default)]
+        created_at: u64,
+        #[serde(default)]
+        draw_offer: Option<Player>,
+        #[serde(default)]
+        takeback_request: Option<Player>,
+        #[serde(default)]
+        clock: Option<Clock>,
+        #[serde(default)]
+        rated: bool,
+        // Bumped by every update, which only applies to the version it was read at
+        #[serde(default)]
+        version: u32,
+        #[serde(default)]
+        ratings_update: Option<RatingsUpdate>,
+        // Older documents keep no castling rights, they cannot tell whether the king or rooks moved
+        #[serde(default)]
+        castling_rights: CastlingRights,
+        #[serde(default)]
+        en_passant: Option<(u8, u8)>,
+        #[serde(default)]
+        halfmove_clock: u16,
+        #[serde(default = "first_fullmove_number")]
+        fullmove_number: u16,
+        #[serde(default)]
+        moves: Vec<JsonString>,
+        #[serde(default)]
+        starting_fen: Option<JsonString>,
+        #[serde(default)]
+        position_history: Vec<JsonString>,
+        #[serde(default)]
+        undo_history: Vec<UndoRecord>,
+    }
+
+    // Stored form of GameState, with the same defaults for older documents
+    #[derive(Encode, Decode, Deserialize, Serialize, Clone, Debug, TypeInfo)]
+    pub struct GameStateLobby {
+        board: [[Option<ChessCell>; 8]; 8],
+        turn: Player,
+        players: PlayersAddressesLobby,
+        #[serde(default)]
+        colour_draw: Option<ColourDraw>,
+        status: GameStatus,
+        #[serde(default)]
+        created_at: u64,
+        #[serde(default)]
+        draw_offer: Option<Player>,
+        #[serde(default)]
+        takeback_request: Option<Player>,
+        #[serde(default)]
+        clock: Option<Clock>,
+        #[serde(default)]
+        rated: bool,
+        // Bumped by every update, which only applies to the version it was read at
+        #[serde(default)]
+        version: u32,
+        #[serde(default)]
+        ratings_update: Option<RatingsUpdate>,
+        // Older documents keep no castling rights, they cannot tell whether the king or rooks moved
+        #[serde(default)]
+        castling_rights: CastlingRights,
+        #[serde(default)]
+        en_passant: Option<(u8, u8)>,
+        #[serde(default)]
+        halfmove_clock: u16,
+        #[serde(default = "first_fullmove_number")]
+        fullmove_number: u16,
+        #[serde(default)]
+        moves: Vec<JsonString>,
+        #[serde(default)]
+        starting_fen: Option<JsonString>,
+        #[serde(default)]
+        position_history: Vec<JsonString>,
+        #[serde(default)]
+        undo_history: Vec<UndoRecord>,
+    }
+
+    #[derive(Encode, Decode, Deserialize, Serialize, Copy, Clone, Debug, PartialEq, TypeInfo)]
+    pub struct ChessCell {
+        piece: Piece,
+        player: Player,
+    }
+
+    #[derive(Encode, Decode, Deserialize, Serialize, Clone, Debug, PartialEq, TypeInfo)]
+    pub struct ChessMove {
+        from: (u8, u8),
+        to: (u8, u8),
+        promotion: Option<Piece>,
+    }
+
+    // Variants in the order of Player, so that a Player argument decodes as the same colour
+    #[derive(Encode, Decode, Copy, Clone, Debug, PartialEq, TypeInfo)]
+    pub enum ColourChoice {
+        Black, White, Random
+    }
+
+    #[derive(Encode, Decode, Deserialize, Serialize, Copy, Clone, Debug, PartialEq, TypeInfo)]
+    pub struct ColourDraw {
+        random_byte: Option<u8>,
+        creator_colour: Option<Player>,
+    }
+
+    // Durations are in milliseconds, like the block timestamps
+    #[derive(Encode, Decode, Deserialize, Serialize, Copy, Clone, Debug, PartialEq, TypeInfo)]
+    pub enum TimeControl {
+        Fischer { base: u64, increment: u64 },
+        Bronstein { base: u64, delay: u64 },
+        Correspondence { days_per_move: u16 },
+    }
+
+    #[derive(Encode, Decode, Deserialize, Serialize, Copy, Clone, Debug, PartialEq, TypeInfo)]
+    pub struct Clock {
+        time_control: TimeControl,
+        white_remaining: u64,
+        black_remaining: u64,
+        last_move_timestamp: u64,
+    }
+
+    // Criteria on the lobbies to list or match with, None accepts anything.
+    // The colour is the one the caller wants to play, that is the free seat of a lobby.
+    #[derive(Encode, Decode, Clone, Debug, Default, TypeInfo)]
+    pub struct LobbyFilters {
+        colour: Option<Player>,
+        time_control: Option<TimeControl>,
+        rated: Option<bool>,
+    }
+
+    #[derive(Encode, Decode, Clone, Debug, PartialEq, TypeInfo)]
+    pub struct OpenLobby {
+        session_id: String,
+        open_seat: Player,
+        random_colour: bool,
+        time_control: Option<TimeControl>,
+        rated: bool,
+        created_at: u64,
+    }
+
+    // Glicko-2 rating, deviation and volatility in fixed point, FIXED_ONE standing for 1.0
+    #[derive(Encode, Decode, Deserialize, Serialize, Clone, Debug, PartialEq, TypeInfo)]
+    pub struct PlayerRating {
+        rating: i64,
+        deviation: i64,
+        volatility: i64,
+        games: u32,
+        // Bumped by every update, which only applies to the version it was read at
+        version: u32,
+        // Session whose result was applied last, so that applying it again is recognised
+        last_session: Option<JsonString>,
+    }
+
+    // Ratings both players had when a rated game ended, and which of their updates are applied yet
+    #[derive(Encode, Decode, Deserialize, Serialize, Clone, Debug, PartialEq, TypeInfo)]
+    pub struct RatingsUpdate {
+        white_before: PlayerRating,
+        black_before: PlayerRating,
+        white_applied: bool,
+        black_applied: bool,
+    }
+
+    impl Default for PlayerRating {
+        fn default() -> Self {
+            PlayerRating {
+                rating: 1500 * FIXED_ONE as i64,
+                deviation: 350 * FIXED_ONE as i64,
+                volatility: 60_000_000, // 0.06
+                games: 0,
+                version: 0,
+                last_session: None,
+            }
+        }
+    }
+
+    // Undo information kept for every move played, so that a takeback restores the previous position exactly
+    #[derive(Encode, Decode, Deserialize, Serialize, Clone, Debug, PartialEq, TypeInfo)]
+    pub struct UndoRecord {
+        chess_move: ChessMove,
+        moved_piece: Piece,
+        captured: Option<ChessCell>,
+        castling_rights: CastlingRights,
+        en_passant: Option<(u8, u8)>,
+        halfmove_clock: u16,
+        // Time left to white and black before the move
+        #[serde(default)]
+        clock_remaining: Option<(u64, u64)>,
+    }
+
+    // serde_json_core cannot deserialize an owned String, only the borrowed str copied here
+    #[derive(Encode, Decode, Serialize, Clone, Debug, PartialEq, TypeInfo)]
+    #[serde(transparent)]
+    pub struct JsonString(String);
+
+    impl<'de> Deserialize<'de> for JsonString {
+        fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> core::result::Result<Self, D::Error> {
+            struct JsonStringVisitor;
+
+            impl<'de> serde::de::Visitor<'de> for JsonStringVisitor {
+                type Value = JsonString;
+
+                fn expecting(&self, formatter: &mut core::fmt::Formatter) -> core::fmt::Result {
+                    formatter.write_str("a string")
+                }
+
+                fn visit_str<E: serde::de::Error>(self, value: &str) -> core::result::Result<JsonString, E> {
+                    Ok(JsonString(String::from(value)))
+                }
+            }
+
+            deserializer.deserialize_str(JsonStringVisitor)
+        }
+    }
+
+    #[derive(Encode, Decode, Deserialize, Clone, Debug)]
+    pub struct FindMongoDBDocumentResult {
+        document: Option<GameStateLobby>
+    }
+
+    #[derive(Deserialize, Clone, Debug)]
+    pub struct MongoDBSessionDocument {
+        sessions: Vec<JsonString>
+    }
+
+    #[derive(Deserialize, Clone, Debug)]
+    pub struct FindMongoDBTrackDocumentResult {
+        document: Option<MongoDBSessionDocument>
+    }
+
+    #[derive(Deserialize, Clone, Debug)]
+    pub struct LobbyDocument {
+        #[serde(rename = "_id")]
+        id: JsonString,
+        players: PlayersAddressesLobby,
+        colour_draw: Option<ColourDraw>,
+        clock: Option<Clock>,
+        #[serde(default)]
+        rated: bool,
+        #[serde(default)]
+        created_at: u64,
+        #[serde(default)]
+        version: u32,
+    }
+
+    #[derive(Deserialize, Clone, Debug)]
+    pub struct FindMongoDBLobbiesResult {
+        documents: Vec<LobbyDocument>
+    }
+
+    #[derive(Deserialize, Clone, Debug)]
+    pub struct FindMongoDBRatingDocumentResult {
+        document: Option<PlayerRating>
+    }
+
+    #[allow(non_snake_case)]
+    #[derive(Deserialize, Clone, Debug)]
+    pub struct InsertedMongoDBDocumentResult {
+        insertedId: Option<JsonString>
+    }
+
+    #[allow(non_snake_case)]
+    #[derive(Deserialize, Clone, Debug)]
+    pub struct UpdatedMongoDBDocumentResult {
+        matchedCount: u32,
+        upsertedId: Option<JsonString>
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use pink::chain_extension::HttpRequest;
+        use core::cell::Cell;
+        use std::rc::Rc;
+
+        const DATA_SOURCE: &str = "TestCluster";
+        const DATABASE: &str = "oh_my_chess_test";
+        const GAME_SESSIONS: &str = "test_game_sessions";
+        const PLAYERS_SESSIONS: &str = "test_players_sessions_trackers";
+        const PLAYER_RATINGS: &str = "test_player_ratings";
+
+        fn new_contract(url: &str) -> OhMyChess {
+            OhMyChess::new(String::from(url), String::from("api-key"), String::from(DATA_SOURCE), String::from(DATABASE), String::from(GAME_SESSIONS), String::from(PLAYERS_SESSIONS), String::from(PLAYER_RATINGS)).unwrap()
+        }
+
+        // The contract keeping its data in the local cache of the worker, mocked in memory
+        fn local_cache_contract() -> OhMyChess {
+            let mut contract = new_contract("https://example.com");
+            assert!(contract.set_storage_backend(StorageBackend::LocalCache));
+            let cache: Rc<RefCell<BTreeMap<Vec<u8>, Vec<u8>>>> = Rc::default();
+            let cache_read = cache.clone();
+            pink::chain_extension::mock::mock_cache_set(move |key, value| {
+                cache.borrow_mut().insert(key.to_vec(), value.to_vec());
+                Ok(())
+            });
+            pink::chain_extension::mock::mock_cache_get(move |key| cache_read.borrow().get(key).cloned());
+            contract
+        }
+
+        /// Converts an algebraic square such as `"e4"` into `(rank, file)` board coordinates
+        fn square(name: &str) -> (u8, u8) {
+            let bytes = name.as_bytes();
+            (bytes[1] - b'1', bytes[0] - b'a')
+        }
+
+        fn board_with(cells: &[(&str, Piece, Player)]) -> Board {
+            let mut board: Board = [[None; 8]; 8];
+            for (name, piece, player) in cells {
+                let (x, y) = square(name);
+                board[x as usize][y as usize] = Some(ChessCell { piece: *piece, player: *player });
+            }
+            board
+        }
+
+        fn play(board: &mut Board, player: Player, from: &str, to: &str) {
+            let chess_move = ChessMove { from: square(from), to: square(to), promotion: None };
+            OhMyChess::update_board_from_move(board, &player, &chess_move).unwrap();
+        }
+
+        fn is_check_mate(board: &Board, player: Player) -> bool {
+            OhMyChess::is_check_mate(board, &player, &CastlingRights::default(), &None).unwrap()
+        }
+
+        #[test]
+        fn fools_mate_is_check_mate() {
+            let mut board = OhMyChess::initial_board();
+            play(&mut board, Player::White, "f2", "f3");
+            play(&mut board, Player::Black, "e7", "e5");
+            play(&mut board, Player::White, "g2", "g4");
+            play(&mut board, Player::Black, "d8", "h4");
+
+            assert!(is_check_mate(&board, Player::White));
+        }
+
+        #[test]
+        fn scholars_mate_is_check_mate() {
+            let mut board = OhMyChess::initial_board();
+            play(&mut board, Player::White, "e2", "e4");
+            play(&mut board, Player::Black, "e7", "e5");
+            play(&mut board, Player::White, "f1", "c4");
+            play(&mut board, Player::Black, "b8", "c6");
+            play(&mut board, Player::White, "d1", "h5");
+            play(&mut board, Player::Black, "g8", "f6");
+            play(&mut board, Player::White, "h5", "f7");
+
+            assert!(is_check_mate(&board, Player::Black));
+        }
+
+        #[test]
+        fn king_stepping_out_of_check_is_not_mate() {
+            let board = board_with(&[
+                ("e1", Piece::King, Player::White),
+                ("e8", Piece::Rook, Player::Black),
+                ("a8", Piece::King, Player::Black),
+            ]);
+
+            assert!(OhMyChess::is_in_check(&board, &Player::White).unwrap());
+            assert!(!is_check_mate(&board, Player::White));
+        }
+
+        #[test]
+        fn back_rank_mate_unless_the_attacker_can_be_captured() {
+            let mut board = board_with(&[
+                ("g1", Piece::King, Player::White),
+                ("f2", Piece::Pawn, Player::White),
+                ("g2", Piece::Pawn, Player::White),
+                ("h2", Piece::Pawn, Player::White),
+                ("a1", Piece::Rook, Player::Black),
+                ("g8", Piece::King, Player::Black),
+            ]);
+            assert!(is_check_mate(&board, Player::White));
+
+            board[7][0] = Some(ChessCell { piece: Piece::Rook, player: Player::White }); // White rook on a8 can take a1
+            assert!(!is_check_mate(&board, Player::White));
+        }
+
+        #[test]
+        fn smothered_mate_unless_the_knight_can_be_captured() {
+            let mut board = board_with(&[
+                ("h1", Piece::King, Player::White),
+                ("g1", Piece::Rook, Player::White),
+                ("g2", Piece::Pawn, Player::White),
+                ("h2", Piece::Pawn, Player::White),
+                ("f2", Piece::Knight, Player::Black),
+                ("e8", Piece::King, Player::Black),
+            ]);
+            assert!(is_check_mate(&board, Player::White));
+
+            board[3][3] = Some(ChessCell { piece: Piece::Bishop, player: Player::White }); // White bishop on d4 can take f2
+            assert!(!is_check_mate(&board, Player::White));
+        }
+
+        #[test]
+        fn double_check_can_only_be_answered_by_a_king_move() {
+            // Rook and bishop both check the king, each check alone could be blocked
+            let mut board = board_with(&[
+                ("a1", Piece::King, Player::White),
+                ("e1", Piece::Rook, Player::White),
+                ("b5", Piece::Bishop, Player::White),
+                ("e8", Piece::King, Player::Black),
+                ("d8", Piece::Rook, Player::Black),
+                ("f8", Piece::Bishop, Player::Black),
+                ("f7", Piece::Pawn, Player::Black),
+            ]);
+            assert!(is_check_mate(&board, Player::Black));
+
+            board[6][5] = None; // Without the f7 pawn the king escapes to f7
+            assert!(!is_check_mate(&board, Player::Black));
+        }
+
+        #[test]
+        fn no_mate_when_not_in_check() {
+            assert!(!is_check_mate(&OhMyChess::initial_board(), Player::White));
+        }
+
+        #[test]
+        fn king_with_no_move_and_not_in_check_is_stalemate() {
+            let board = board_with(&[
+                ("h8", Piece::King, Player::Black),
+                ("f7", Piece::King, Player::White),
+                ("g6", Piece::Queen, Player::White),
+            ]);
+
+            assert!(OhMyChess::is_stalemate(&board, &Player::Black, &CastlingRights::default(), &None).unwrap());
+            assert!(!is_check_mate(&board, Player::Black));
+        }
+
+        #[test]
+        fn blocked_pawn_left_is_stalemate_but_a_free_one_is_not() {
+            let mut board = board_with(&[
+                ("a8", Piece::King, Player::Black),
+                ("c7", Piece::Queen, Player::White),
+                ("e1", Piece::King, Player::White),
+                ("h4", Piece::Pawn, Player::Black),
+                ("h3", Piece::Pawn, Player::White),
+            ]);
+            assert!(OhMyChess::is_stalemate(&board, &Player::Black, &CastlingRights::default(), &None).unwrap());
+
+            board[2][7] = None; // Without the h3 pawn, black can still push h4-h3
+            assert!(!OhMyChess::is_stalemate(&board, &Player::Black, &CastlingRights::default(), &None).unwrap());
+        }
+
+        #[test]
+        fn stalemate_ends_the_game() {
+            let mut game_state = game_state_from_fen("7k/5K2/6Q1/8/8/8/8/8 w - - 0 1");
+
+            OhMyChess::update_turn_and_status(&mut game_state).unwrap();
+            assert_eq!(game_state.status, GameStatus::Stalemate);
+            assert_eq!(game_state.turn, Player::Black);
+        }
+
+        fn count_attacked_squares(board: &Board, attacker: Player) -> usize {
+            OhMyChess::attack_map(board, &attacker).iter().flatten().filter(|attacked| **attacked).count()
+        }
+
+        /// Sums, over every square of an otherwise empty board, the squares attacked by a lone piece
+        fn total_attacks_from_every_square(piece: Piece, player: Player) -> usize {
+            let mut total = 0;
+            for x in 0..8 {
+                for y in 0..8 {
+                    let mut board: Board = [[None; 8]; 8];
+                    board[x][y] = Some(ChessCell { piece, player });
+                    total += count_attacked_squares(&board, player);
+                }
+            }
+            total
+        }
+
+        #[test]
+        fn lone_pieces_attack_the_known_number_of_squares() {
+            assert_eq!(total_attacks_from_every_square(Piece::Knight, Player::White), 336);
+            assert_eq!(total_attacks_from_every_square(Piece::Bishop, Player::White), 560);
+            assert_eq!(total_attacks_from_every_square(Piece::Rook, Player::Black), 896);
+            assert_eq!(total_attacks_from_every_square(Piece::Queen, Player::Black), 1456);
+            assert_eq!(total_attacks_from_every_square(Piece::King, Player::White), 420);
+            // Pawns attack two squares, one on the edge files, from every rank but their last one
+            assert_eq!(total_attacks_from_every_square(Piece::Pawn, Player::White), 98);
+            assert_eq!(total_attacks_from_every_square(Piece::Pawn, Player::Black), 98);
+        }
+
+        #[test]
+        fn pawns_attack_diagonally_forward_in_their_own_direction() {
+            let board = board_with(&[
+                ("e4", Piece::Pawn, Player::White),
+                ("d5", Piece::Pawn, Player::Black),
+            ]);
+
+            let white_attacks = OhMyChess::attack_map(&board, &Player::White);
+            assert!(white_attacks[4][3] && white_attacks[4][5]); // d5 and f5
+            assert_eq!(count_attacked_squares(&board, Player::White), 2);
+
+            let black_attacks = OhMyChess::attack_map(&board, &Player::Black);
+            assert!(black_attacks[3][2] && black_attacks[3][4]); // c4 and e4
+            assert_eq!(count_attacked_squares(&board, Player::Black), 2);
+        }
+
+        #[test]
+        fn sliding_attacks_stop_on_the_first_piece() {
+            let board = board_with(&[
+                ("a1", Piece::Rook, Player::White),
+                ("a4", Piece::Pawn, Player::Black),
+                ("d1", Piece::Knight, Player::White),
+            ]);
+
+            assert!(OhMyChess::is_square_attacked_by(&board, square("a4"), &Player::White));
+            assert!(!OhMyChess::is_square_attacked_by(&board, square("a5"), &Player::White));
+            assert!(OhMyChess::is_square_attacked_by(&board, square("d1"), &Player::White)); // Own pieces are defended
+            assert!(!OhMyChess::is_square_attacked_by(&board, square("e1"), &Player::White));
+        }
+
+        #[test]
+        fn initial_position_attack_maps() {
+            let board = OhMyChess::initial_board();
+            assert_eq!(count_attacked_squares(&board, Player::White), 22);
+            assert_eq!(count_attacked_squares(&board, Player::Black), 22);
+        }
+
+        #[test]
+        fn pawn_checks_follow_the_attacking_pawn_colour() {
+            let checked_by_white_pawn = board_with(&[("e8", Piece::King, Player::Black), ("d7", Piece::Pawn, Player::White), ("a1", Piece::King, Player::White)]);
+            assert!(OhMyChess::is_in_check(&checked_by_white_pawn, &Player::Black).unwrap());
+
+            let checked_by_black_pawn = board_with(&[("e1", Piece::King, Player::White), ("f2", Piece::Pawn, Player::Black), ("a8", Piece::King, Player::Black)]);
+            assert!(OhMyChess::is_in_check(&checked_by_black_pawn, &Player::White).unwrap());
+
+            // A pawn right in front or behind the king does not give check
+            let pawn_in_front = board_with(&[("e8", Piece::King, Player::Black), ("e7", Piece::Pawn, Player::White), ("a1", Piece::King, Player::White)]);
+            assert!(!OhMyChess::is_in_check(&pawn_in_front, &Player::Black).unwrap());
+
+            let pawn_behind = board_with(&[("e6", Piece::King, Player::Black), ("f7", Piece::Pawn, Player::White), ("a1", Piece::King, Player::White)]);
+            assert!(!OhMyChess::is_in_check(&pawn_behind, &Player::Black).unwrap());
+
+            let black_pawn_behind = board_with(&[("e3", Piece::King, Player::White), ("d2", Piece::Pawn, Player::Black), ("a8", Piece::King, Player::Black)]);
+            assert!(!OhMyChess::is_in_check(&black_pawn_behind, &Player::White).unwrap());
+        }
+
+        #[test]
+        fn square_guarded_by_a_pawn_makes_stalemate() {
+            let board = board_with(&[
+                ("a8", Piece::King, Player::Black),
+                ("a7", Piece::Pawn, Player::White),
+                ("b6", Piece::King, Player::White),
+            ]);
+
+            assert!(OhMyChess::is_stalemate(&board, &Player::Black, &CastlingRights::default(), &None).unwrap());
+        }
+
+        #[test]
+        fn castling_through_a_square_attacked_by_a_pawn_is_rejected() {
+            let castling_rights = CastlingRights { white_king_side: true, white_queen_side: true, black_king_side: false, black_queen_side: false };
+            let mut board = board_with(&[
+                ("e1", Piece::King, Player::White),
+                ("h1", Piece::Rook, Player::White),
+                ("a1", Piece::Rook, Player::White),
+                ("e8", Piece::King, Player::Black),
+                ("g2", Piece::Pawn, Player::Black), // Attacks f1 and h1
+            ]);
+            let king_side = ChessMove { from: square("e1"), to: square("g1"), promotion: None };
+            let queen_side = ChessMove { from: square("e1"), to: square("c1"), promotion: None };
+
+            assert!(OhMyChess::check_move_validity_king(&board, &Player::White, &castling_rights, &king_side).is_err());
+            assert!(OhMyChess::check_move_validity_king(&board, &Player::White, &castling_rights, &queen_side).is_ok());
+
+            board[1][6] = Some(ChessCell { piece: Piece::Pawn, player: Player::White }); // A white pawn on g2 attacks nothing on the first rank
+            assert!(OhMyChess::check_move_validity_king(&board, &Player::White, &castling_rights, &king_side).is_ok());
+        }
+
+        #[test]
+        fn initial_position_has_twenty_legal_moves() {
+            let legal_moves = OhMyChess::legal_moves_for_board(&OhMyChess::initial_board(), &Player::White, &CastlingRights::default(), &None).unwrap();
+            assert_eq!(legal_moves.len(), 20);
+        }
+
+        #[test]
+        fn legal_moves_include_every_promotion_en_passant_and_castling() {
+            let castling_rights = CastlingRights { white_king_side: true, white_queen_side: false, black_king_side: false, black_queen_side: false };
+            let board = board_with(&[
+                ("e1", Piece::King, Player::White),
+                ("h1", Piece::Rook, Player::White),
+                ("b7", Piece::Pawn, Player::White),
+                ("e5", Piece::Pawn, Player::White),
+                ("d5", Piece::Pawn, Player::Black),
+                ("h8", Piece::King, Player::Black),
+            ]);
+            let legal_moves = OhMyChess::legal_moves_for_board(&board, &Player::White, &castling_rights, &Some(square("d6"))).unwrap();
+
+            let promotions: Vec<_> = legal_moves.iter().filter(|legal_move| legal_move.from == square("b7")).collect();
+            assert_eq!(promotions.len(), 4);
+            assert!(promotions.iter().all(|promotion| promotion.promotion.is_some()));
+
+            assert!(legal_moves.contains(&ChessMove { from: square("e5"), to: square("d6"), promotion: None }));
+            assert!(legal_moves.contains(&ChessMove { from: square("e1"), to: square("g1"), promotion: None }));
+            assert!(!legal_moves.contains(&ChessMove { from: square("e1"), to: square("c1"), promotion: None }));
+        }
+
+        fn position_from_fen(fen: &str) -> GameStateLobby {
+            OhMyChess::game_state_lobby_from_fen(fen, PlayersAddressesLobby { black: None, white: None }).unwrap()
+        }
+
+        fn game_state_from_fen(fen: &str) -> GameState {
+            OhMyChess::game_state_from_lobby(position_from_fen(fen), PlayersAddresses { black: [1; 32], white: [2; 32] })
+        }
+
+        /// Counts the leaf nodes of the legal move tree down to the given depth
+        fn perft(board: &Board, player: Player, castling_rights: CastlingRights, en_passant: Option<(u8, u8)>, depth: u32) -> u64 {
+            let legal_moves = OhMyChess::legal_moves_for_board(board, &player, &castling_rights, &en_passant).unwrap();
+            if depth == 1 {
+                return legal_moves.len() as u64;
+            }
+
+            let mut nodes = 0;
+            for legal_move in legal_moves {
+                let mut next_board = *board;
+                let mut next_castling_rights = castling_rights;
+                let mut next_en_passant = en_passant;
+                OhMyChess::update_board_from_move(&mut next_board, &player, &legal_move).unwrap();
+                OhMyChess::update_castling_rights(&mut next_castling_rights, &legal_move);
+                OhMyChess::update_en_passant_target(&mut next_en_passant, &next_board, &legal_move);
+                nodes += perft(&next_board, OhMyChess::opposing_player(&player), next_castling_rights, next_en_passant, depth - 1);
+            }
+            nodes
+        }
+
+        fn assert_perft(fen: &str, expected_nodes: &[u64]) {
+            let position = position_from_fen(fen);
+            for (depth, expected) in expected_nodes.iter().enumerate() {
+                let nodes = perft(&(position.board), position.turn, position.castling_rights, position.en_passant, depth as u32 + 1);
+                assert_eq!(nodes, *expected, "perft({}) of {}", depth + 1, fen);
+            }
+        }
+
+        #[test]
+        fn perft_initial_position() {
+            assert_perft("rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1", &[20, 400, 8902, 197281]);
+        }
+
+        #[test]
+        fn perft_kiwipete() {
+            assert_perft("r3k2r/p1ppqpb1/bn2pnp1/3PN3/1p2P3/2N2Q1p/PPPBBPPP/R3K2R w KQkq - 0 1", &[48, 2039, 97862]);
+        }
+
+        #[test]
+        fn perft_en_passant_and_discovered_checks_endgame() {
+            assert_perft("8/2p5/3p4/KP5r/1R3p1k/8/4P1P1/8 w - - 0 1", &[14, 191, 2812, 43238]);
+        }
+
+        #[test]
+        fn perft_promotions_and_castling_rights_losses() {
+            assert_perft("r3k2r/Pppp1ppp/1b3nbN/nP6/BBP1P3/q4N2/Pp1P2PP/R2Q1RK1 w kq - 0 1", &[6, 264, 9467]);
+        }
+
+        #[test]
+        fn perft_promotion_with_check() {
+            assert_perft("rnbq1k1r/pp1Pbppp/2p5/8/2B5/8/PPP1NnPP/RNBQK2R w KQ - 1 8", &[44, 1486, 62379]);
+        }
+
+        #[test]
+        fn perft_middlegame() {
+            assert_perft("r4rk1/1pp1qppp/p1np1n2/2b1p1B1/2B1P1b1/P1NP1N2/1PP1QPPP/R4RK1 w - - 0 10", &[46, 2079, 89890]);
+        }
+
+        const INITIAL_FEN: &str = "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1";
+
+        #[test]
+        fn fen_of_the_initial_position_round_trips() {
+            let position = position_from_fen(INITIAL_FEN);
+            assert_eq!(position.board, OhMyChess::initial_board());
+            assert_eq!(OhMyChess::game_state_lobby_to_fen(&position), INITIAL_FEN);
+        }
+
+        #[test]
+        fn fen_round_trips_castling_en_passant_and_counters() {
+            for fen in [
+                "rnbqkbnr/ppp1p1pp/8/3pPp2/8/8/PPPP1PPP/RNBQKBNR w KQkq f6 0 3",
+                "r3k2r/p1ppqpb1/bn2pnp1/3PN3/1p2P3/2N2Q1p/PPPBBPPP/R3K2R w KQkq - 0 1",
+                "8/2p5/3p4/KP5r/1R3p1k/8/4P1P1/8 b - - 12 47",
+                "4k3/8/8/8/3Pp3/8/8/4K3 b - d3 0 1",
+            ] {
+                assert_eq!(OhMyChess::game_state_lobby_to_fen(&position_from_fen(fen)), fen);
+            }
+        }
+
+        #[test]
+        fn fen_without_move_counters_defaults_them() {
+            let position = position_from_fen("4k3/8/8/8/8/8/8/4K3 w - -");
+            assert_eq!((position.halfmove_clock, position.fullmove_number), (0, 1));
+        }
+
+        #[test]
+        fn malformed_fen_is_rejected() {
+            for fen in [
+                "",
+                "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP w KQkq - 0 1", // 7 ranks
+                "rnbqkbnr/pppppppp/9/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1", // Rank too long
+                "rnbqkbnr/ppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1", // Rank too short
+                "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNX w KQkq - 0 1", // Unknown piece
+                "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQ1BNR w kq - 0 1", // No white king
+                "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR x KQkq - 0 1", // Side to move
+                "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBN1 w KQkq - 0 1", // Castling right without the rook
+                "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq e3 0 1", // En passant square without the pawn
+                "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - x 1", // Halfmove clock
+                "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 0", // Fullmove number
+                "4k2P/8/8/8/8/8/8/4K3 w - - 0 1", // Pawn on the last rank
+                "4k2R/8/8/8/8/8/8/4K3 w - - 0 1", // Side not to move is in check
+            ] {
+                let result = OhMyChess::game_state_lobby_from_fen(fen, PlayersAddressesLobby { black: None, white: None });
+                assert_eq!(result.err(), Some(NonValidFen), "{}", fen);
+            }
+        }
+
+        #[test]
+        fn move_counters_follow_pawn_moves_and_captures() {
+            let mut game_state = game_state_from_fen("4k3/8/8/8/8/8/4P3/R3K3 w - - 5 10");
+
+            OhMyChess::update_move_counters(&mut game_state, &ChessMove { from: square("a1"), to: square("a2"), promotion: None });
+            assert_eq!((game_state.halfmove_clock, game_state.fullmove_number), (6, 10));
+
+            game_state.turn = Player::Black;
+            OhMyChess::update_move_counters(&mut game_state, &ChessMove { from: square("e8"), to: square("d8"), promotion: None });
+            assert_eq!((game_state.halfmove_clock, game_state.fullmove_number), (7, 11));
+
+            game_state.turn = Player::White;
+            OhMyChess::update_move_counters(&mut game_state, &ChessMove { from: square("e2"), to: square("e4"), promotion: None });
+            assert_eq!((game_state.halfmove_clock, game_state.fullmove_number), (0, 11));
+        }
+
+        /// Plays a legal move given in coordinates, returning it in SAN
+        fn play_san(position: &mut GameStateLobby, from: &str, to: &str, promotion: Option<Piece>) -> String {
+            let chess_move = ChessMove { from: square(from), to: square(to), promotion };
+            let player = position.turn;
+            let san = OhMyChess::san_from_move(&(position.board), &player, &(position.castling_rights), &(position.en_passant), &chess_move).unwrap();
+            OhMyChess::update_board_from_move(&mut position.board, &player, &chess_move).unwrap();
+            OhMyChess::update_castling_rights(&mut position.castling_rights, &chess_move);
+            OhMyChess::update_en_passant_target(&mut position.en_passant, &(position.board), &chess_move);
+            position.turn = OhMyChess::opposing_player(&player);
+            position.moves.push(JsonString(san.clone()));
+            san
+        }
+
+        #[test]
+        fn san_of_an_opening_with_captures_and_castling() {
+            let mut position = position_from_fen(INITIAL_FEN);
+            let sans: Vec<String> = [
+                ("e2", "e4"), ("e7", "e5"), ("g1", "f3"), ("b8", "c6"), ("f1", "b5"),
+                ("a7", "a6"), ("b5", "c6"), ("d7", "c6"), ("e1", "g1"),
+            ].iter().map(|(from, to)| play_san(&mut position, from, to, None)).collect();
+
+            assert_eq!(sans, ["e4", "e5", "Nf3", "Nc6", "Bb5", "a6", "Bxc6", "dxc6", "O-O"]);
+        }
+
+        #[test]
+        fn san_disambiguates_by_file_then_rank_then_square() {
+            let mut position = position_from_fen("4k3/8/8/R7/8/Q7/8/QNQ1K2R w K - 0 1");
+            assert_eq!(play_san(&mut position.clone(), "a5", "a4", None), "Ra4");
+            assert_eq!(play_san(&mut position.clone(), "h1", "f1", None), "Rf1");
+            assert_eq!(play_san(&mut position.clone(), "a1", "b2", None), "Qa1b2");
+            assert_eq!(play_san(&mut position.clone(), "c1", "b2", None), "Qcb2");
+            assert_eq!(play_san(&mut position.clone(), "a3", "b2", None), "Q3b2");
+            assert_eq!(play_san(&mut position.clone(), "e1", "g1", None), "O-O");
+
+            position = position_from_fen("4k3/8/8/8/8/5N2/8/RN2K3 w - - 0 1");
+            assert_eq!(play_san(&mut position.clone(), "b1", "d2", None), "Nbd2");
+            position = position_from_fen("4k3/8/8/R7/8/8/8/R3K3 w - - 0 1");
+            assert_eq!(play_san(&mut position.clone(), "a1", "a3", None), "R1a3");
+        }
+
+        #[test]
+        fn san_of_promotions_en_passant_checks_and_mates() {
+            let position = position_from_fen("3r3k/4P3/8/3pP3/8/8/8/4K3 w - d6 0 1");
+            assert_eq!(play_san(&mut position.clone(), "e7", "e8", None), "e8=Q+");
+            assert_eq!(play_san(&mut position.clone(), "e7", "d8", Some(Piece::Knight)), "exd8=N");
+            assert_eq!(play_san(&mut position.clone(), "e5", "d6", None), "exd6");
+
+            let mut fools_mate = position_from_fen(INITIAL_FEN);
+            play_san(&mut fools_mate, "f2", "f3", None);
+            play_san(&mut fools_mate, "e7", "e5", None);
+            play_san(&mut fools_mate, "g2", "g4", None);
+            assert_eq!(play_san(&mut fools_mate, "d8", "h4", None), "Qh4#");
+        }
+
+        #[test]
+        fn pgn_export_of_a_finished_game() {
+            let mut position = position_from_fen(INITIAL_FEN);
+            position.players = PlayersAddressesLobby { white: Some([0xaa; 32]), black: None };
+            play_san(&mut position, "f2", "f3", None);
+            play_san(&mut position, "e7", "e5", None);
+            play_san(&mut position, "g2", "g4", None);
+            play_san(&mut position, "d8", "h4", None);
+            position.status = GameStatus::WonByPlayerBlack;
+
+            let white = OhMyChess::bytes_to_hex_string([0xaa; 32]).unwrap();
+            let expected = format!(
+                "[Event \"Oh My Chess game\"]\n[Site \"Phala Network\"]\n[Date \"????.??.??\"]\n[Round \"-\"]\n[White \"{}\"]\n[Black \"?\"]\n[Result \"0-1\"]\n\n1. f3 e5 2. g4 Qh4# 0-1\n",
+                white,
+            );
+            assert_eq!(OhMyChess::game_state_lobby_to_pgn(&position).unwrap(), expected);
+        }
+
+        #[test]
+        fn pgn_date_is_the_utc_day_of_the_creation() {
+            assert_eq!(OhMyChess::pgn_date(0), "????.??.??");
+            assert_eq!(OhMyChess::pgn_date(1), "1970.01.01");
+            assert_eq!(OhMyChess::pgn_date(951_782_400_000), "2000.02.29");
+            assert_eq!(OhMyChess::pgn_date(1_709_251_199_999), "2024.02.29");
+            assert_eq!(OhMyChess::pgn_date(1_709_251_200_000), "2024.03.01");
+            assert_eq!(OhMyChess::pgn_date(1_735_689_599_999), "2024.12.31");
+
+            let mut position = position_from_fen(INITIAL_FEN);
+            position.created_at = 1_709_251_200_000;
+            assert!(OhMyChess::game_state_lobby_to_pgn(&position).unwrap().contains("[Date \"2024.03.01\"]\n"));
+        }
+
+        #[test]
+        fn pgn_export_of_a_game_started_from_fen_with_black_to_move() {
+            let fen = "4k3/8/8/8/8/8/4P3/4K3 b - - 0 30";
+            let mut position = position_from_fen(fen);
+            position.starting_fen = Some(JsonString(String::from(fen)));
+            play_san(&mut position, "e8", "d7", None);
+            play_san(&mut position, "e2", "e4", None);
+
+            let pgn = OhMyChess::game_state_lobby_to_pgn(&position).unwrap();
+            assert!(pgn.contains("[SetUp \"1\"]\n[FEN \"4k3/8/8/8/8/8/4P3/4K3 b - - 0 30\"]\n"));
+            assert!(pgn.ends_with("\n\n30... Kd7 31. e4 *\n"));
+        }
+
+        #[test]
+        fn pgn_movetext_lines_stay_under_80_characters() {
+            let mut position = position_from_fen(INITIAL_FEN);
+            for _ in 0..20 {
+                play_san(&mut position, "g1", "f3", None);
+                play_san(&mut position, "g8", "f6", None);
+                play_san(&mut position, "f3", "g1", None);
+                play_san(&mut position, "f6", "g8", None);
+            }
+
+            let pgn = OhMyChess::game_state_lobby_to_pgn(&position).unwrap();
+            assert!(pgn.lines().all(|line| line.len() < 80));
+            assert_eq!(pgn.matches("Nf3").count(), 20);
+        }
+
+        #[test]
+        fn game_state_with_history_round_trips_through_json() {
+            let mut position = position_from_fen(INITIAL_FEN);
+            position.starting_fen = Some(JsonString(String::from(INITIAL_FEN)));
+            play_san(&mut position, "e2", "e4", None);
+            play_san(&mut position, "c7", "c5", None);
+            position.clock = Some(Clock { time_control: TimeControl::Bronstein { base: 300_000, delay: 5_000 }, white_remaining: 298_000, black_remaining: 1_700_000_000_000, last_move_timestamp: 1_700_000_000_000 });
+
+            let json = serde_json::to_string(&position).unwrap();
+            let (decoded, _) = serde_json_core::from_slice::<GameStateLobby>(json.as_bytes()).unwrap();
+            assert_eq!(decoded.moves, position.moves);
+            assert_eq!(decoded.starting_fen, position.starting_fen);
+            assert_eq!(decoded.en_passant, Some(square("c6")));
+            assert_eq!(decoded.clock, position.clock);
+            assert_eq!(OhMyChess::game_state_lobby_to_fen(&decoded), OhMyChess::game_state_lobby_to_fen(&position));
+        }
+
+        /// Plays a legal move given in coordinates the way make_move does, once validated
+        fn play_move(game_state: &mut GameState, from: &str, to: &str) {
+            let chess_move = ChessMove { from: square(from), to: square(to), promotion: None };
+            let player = game_state.turn;
+            let undo_record = OhMyChess::undo_record_from_move(game_state, &chess_move).unwrap();
+            game_state.undo_history.push(undo_record);
+            OhMyChess::update_move_counters(game_state, &chess_move);
+            OhMyChess::update_board_from_move(&mut game_state.board, &player, &chess_move).unwrap();
+            OhMyChess::update_castling_rights(&mut game_state.castling_rights, &chess_move);
+            OhMyChess::update_en_passant_target(&mut game_state.en_passant, &(game_state.board), &chess_move);
+            OhMyChess::update_draw_offer(&mut game_state.draw_offer, &player);
+            OhMyChess::update_turn_and_status(game_state).unwrap();
+        }
+
+        fn shuffle_knights(game_state: &mut GameState) {
+            play_move(game_state, "g1", "f3");
+            play_move(game_state, "g8", "f6");
+            play_move(game_state, "f3", "g1");
+            play_move(game_state, "f6", "g8");
+        }
+
+        #[test]
+        fn threefold_repetition_can_be_claimed_and_fivefold_ends_the_game() {
+            let mut game_state = game_state_from_fen(INITIAL_FEN);
+            shuffle_knights(&mut game_state);
+            assert_eq!(OhMyChess::repetition_count(&(game_state.position_history)), 2);
+            assert!(!OhMyChess::can_claim_draw(&game_state));
+
+            shuffle_knights(&mut game_state);
+            assert_eq!(OhMyChess::repetition_count(&(game_state.position_history)), 3);
+            assert!(OhMyChess::can_claim_draw(&game_state));
+            assert_eq!(game_state.status, GameStatus::Ongoing);
+
+            shuffle_knights(&mut game_state);
+            assert_eq!(game_state.status, GameStatus::Ongoing);
+            shuffle_knights(&mut game_state);
+            assert_eq!(OhMyChess::repetition_count(&(game_state.position_history)), 5);
+            assert_eq!(game_state.status, GameStatus::Draw);
+        }
+
+        #[test]
+        fn same_board_with_another_player_to_move_is_another_position() {
+            let mut game_state = game_state_from_fen("4k3/8/8/8/8/8/8/R3K3 w - - 0 1");
+            play_move(&mut game_state, "a1", "a2");
+            play_move(&mut game_state, "e8", "d8");
+            play_move(&mut game_state, "a2", "a3");
+            play_move(&mut game_state, "d8", "e8");
+            play_move(&mut game_state, "a3", "a1"); // Same board as the start, but black to move
+            assert_eq!(OhMyChess::repetition_count(&(game_state.position_history)), 1);
+        }
+
+        #[test]
+        fn lost_castling_rights_make_another_position() {
+            let mut game_state = game_state_from_fen("r3k3/8/8/8/8/8/8/R3K3 w Qq - 0 1");
+            play_move(&mut game_state, "a1", "b1");
+            play_move(&mut game_state, "a8", "b8");
+            play_move(&mut game_state, "b1", "a1");
+            play_move(&mut game_state, "b8", "a8");
+            assert_eq!(OhMyChess::repetition_count(&(game_state.position_history)), 1);
+        }
+
+        #[test]
+        fn en_passant_square_only_matters_when_the_capture_is_possible() {
+            let board = position_from_fen("4k3/8/8/8/4P3/8/8/4K3 b - - 0 1").board;
+            let no_capture = OhMyChess::position_hash(&board, &Player::Black, &CastlingRights::default(), &Some(square("e3"))).unwrap();
+            assert_eq!(no_capture, OhMyChess::position_hash(&board, &Player::Black, &CastlingRights::default(), &None).unwrap());
+
+            let board = position_from_fen("4k3/8/8/8/3pP3/8/8/4K3 b - - 0 1").board;
+            let capture = OhMyChess::position_hash(&board, &Player::Black, &CastlingRights::default(), &Some(square("e3"))).unwrap();
+            assert_ne!(capture, OhMyChess::position_hash(&board, &Player::Black, &CastlingRights::default(), &None).unwrap());
+        }
+
+        #[test]
+        fn fifty_move_rule_can_be_claimed_and_seventy_five_move_rule_ends_the_game() {
+            let mut game_state = game_state_from_fen("4k3/8/8/8/8/8/8/R3K3 w - - 98 80");
+            assert!(!OhMyChess::can_claim_draw(&game_state));
+            play_move(&mut game_state, "a1", "a2");
+            play_move(&mut game_state, "e8", "d8");
+            assert!(OhMyChess::can_claim_draw(&game_state));
+            assert_eq!(game_state.status, GameStatus::Ongoing);
+
+            game_state.halfmove_clock = 149;
+            play_move(&mut game_state, "a2", "a3");
+            assert_eq!(game_state.status, GameStatus::Draw);
+        }
+
+        #[test]
+        fn checkmate_on_the_seventy_fifth_move_still_wins() {
+            let mut game_state = game_state_from_fen("6k1/5ppp/8/8/8/8/8/R5K1 w - - 149 120");
+            play_move(&mut game_state, "a1", "a8");
+            assert_eq!(game_state.status, GameStatus::WonByPlayerWhite);
+        }
+
+        #[test]
+        fn insufficient_material_positions() {
+            for fen in [
+                "4k3/8/8/8/8/8/8/4K3 w - - 0 1", // King against king
+                "4k3/8/8/8/8/8/8/2B1K3 w - - 0 1", // King and bishop against king
+                "4k3/8/8/8/8/8/8/1N2K3 w - - 0 1", // King and knight against king
+                "2b1k3/8/8/8/8/8/8/3BK3 w - - 0 1", // Bishops on squares of the same colour
+                "4k3/8/8/8/8/8/8/B1B1K1B1 w - - 0 1", // Same coloured bishops, whatever their number
+            ] {
+                assert!(OhMyChess::is_insufficient_material(&position_from_fen(fen).board), "{}", fen);
+            }
+
+            for fen in [
+                "4k3/8/8/8/8/8/4P3/4K3 w - - 0 1", // A pawn can still promote
+                "4k3/8/8/8/8/8/8/R3K3 w - - 0 1",
+                "4k3/8/8/8/8/8/8/3QK3 w - - 0 1",
+                "2b1k3/8/8/8/8/8/8/2B1K3 w - - 0 1", // Bishops on squares of different colours
+                "4k3/8/8/8/8/8/8/1NB1K3 w - - 0 1", // Knight and bishop
+                "1n2k3/8/8/8/8/8/8/1N2K3 w - - 0 1", // Knight against knight can still mate
+                "4k3/8/8/8/8/8/8/NN2K3 w - - 0 1",
+            ] {
+                assert!(!OhMyChess::is_insufficient_material(&position_from_fen(fen).board), "{}", fen);
+            }
+        }
+
+        #[test]
+        fn capturing_the_last_piece_that_could_mate_ends_the_game_as_a_draw() {
+            let mut game_state = game_state_from_fen("4k3/8/8/8/8/8/3r4/4K3 w - - 0 1");
+            play_move(&mut game_state, "e1", "d2");
+            assert_eq!(game_state.status, GameStatus::Draw);
+        }
+
+        #[test]
+        fn caller_must_be_a_player_but_not_necessarily_the_one_to_move() {
+            ink::env::test::set_caller::<PinkEnvironment>(AccountId::from([1; 32]));
+            let contract = new_contract("https://example.com");
+            let game_state = game_state_from_fen(INITIAL_FEN);
+
+            assert_eq!(contract.caller_player(&game_state), Ok(Player::Black));
+            assert_eq!(contract.check_caller_turn(&game_state), Err(NotYourTurn));
+
+            ink::env::test::set_caller::<PinkEnvironment>(AccountId::from([2; 32]));
+            assert_eq!(contract.caller_player(&game_state), Ok(Player::White));
+            assert_eq!(contract.check_caller_turn(&game_state), Ok(()));
+
+            ink::env::test::set_caller::<PinkEnvironment>(AccountId::from([3; 32]));
+            assert_eq!(contract.caller_player(&game_state), Err(NotInThisGameSession));
+            assert_eq!(contract.check_caller_turn(&game_state), Err(NotInThisGameSession));
+        }
+
+        #[test]
+        fn draw_offer_is_answered_by_the_opponent_only() {
+            ink::env::test::set_caller::<PinkEnvironment>(AccountId::from([1; 32]));
+            let contract = new_contract("https://example.com");
+            let mut game_state = game_state_from_fen(INITIAL_FEN);
+
+            // Nothing to answer before an offer is made
+            assert_eq!(contract.check_draw_offered_to_caller(&game_state), Err(NoDrawOffer));
+
+            // Black offers, so only white can answer
+            game_state.draw_offer = Some(Player::Black);
+            assert_eq!(contract.check_draw_offered_to_caller(&game_state), Err(NoDrawOffer));
+            ink::env::test::set_caller::<PinkEnvironment>(AccountId::from([2; 32]));
+            assert_eq!(contract.check_draw_offered_to_caller(&game_state), Ok(()));
+            ink::env::test::set_caller::<PinkEnvironment>(AccountId::from([3; 32]));
+            assert_eq!(contract.check_draw_offered_to_caller(&game_state), Err(NotInThisGameSession));
+        }
+
+        #[test]
+        fn draw_offer_expires_when_the_opponent_moves() {
+            let mut game_state = game_state_from_fen(INITIAL_FEN);
+
+            // White offers a draw then moves: the offer stays on the table for black
+            game_state.draw_offer = Some(Player::White);
+            play_move(&mut game_state, "e2", "e4");
+            assert_eq!(game_state.draw_offer, Some(Player::White));
+
+            // Black moves instead of answering
+            play_move(&mut game_state, "e7", "e5");
+            assert_eq!(game_state.draw_offer, None);
+        }
+
+        fn assert_same_position(game_state: &GameState, expected: &GameState) {
+            assert_eq!(game_state.board, expected.board);
+            assert_eq!(game_state.turn, expected.turn);
+            assert_eq!(game_state.castling_rights, expected.castling_rights);
+            assert_eq!(game_state.en_passant, expected.en_passant);
+            assert_eq!(game_state.halfmove_clock, expected.halfmove_clock);
+            assert_eq!(game_state.fullmove_number, expected.fullmove_number);
+            assert_eq!(game_state.moves, expected.moves);
+            assert_eq!(game_state.position_history, expected.position_history);
+            assert_eq!(game_state.undo_history, expected.undo_history);
+        }
+
+        #[test]
+        fn takeback_restores_castling_en_passant_and_promotions() {
+            // White can castle, capture en passant after d7-d5 and promote on g8 capturing the knight
+            let mut game_state = game_state_from_fen("rn2k1n1/3p2P1/8/4P3/8/8/8/R3K2R b KQq - 3 20");
+            let expected = game_state.clone();
+
+            for (from, to) in [("d7", "d5"), ("e5", "d6"), ("e8", "f7"), ("g7", "g8"), ("b8", "c6"), ("e1", "c1")] {
+                play_move(&mut game_state, from, to);
+            }
+            assert_eq!(game_state.undo_history.len(), 6);
+
+            while !game_state.undo_history.is_empty() {
+                OhMyChess::undo_last_move(&mut game_state).unwrap();
+            }
+            assert_same_position(&game_state, &expected);
+            assert_eq!(OhMyChess::undo_last_move(&mut game_state), Err(NoMoveToTakeBack));
+        }
+
+        #[test]
+        fn takeback_reverts_until_the_requesting_player_is_to_move_again() {
+            let mut game_state = game_state_from_fen(INITIAL_FEN);
+            play_move(&mut game_state, "e2", "e4");
+            let after_white_move = game_state.clone();
+            play_move(&mut game_state, "e7", "e5");
+            let after_black_move = game_state.clone();
+
+            // Black asks right after moving: only their move is taken back
+            OhMyChess::take_back_moves(&mut game_state, &Player::Black).unwrap();
+            assert_same_position(&game_state, &after_white_move);
+
+            // White asks once black has replied: the whole move is taken back
+            let mut game_state = after_black_move;
+            OhMyChess::take_back_moves(&mut game_state, &Player::White).unwrap();
+            assert_same_position(&game_state, &game_state_from_fen(INITIAL_FEN));
+        }
+
+        fn clock(time_control: TimeControl, white_remaining: u64, black_remaining: u64) -> Clock {
+            Clock { time_control, white_remaining, black_remaining, last_move_timestamp: 1_000 }
+        }
+
+        #[test]
+        fn fischer_increment_is_added_after_the_thinking_time() {
+            let mut fischer = clock(TimeControl::Fischer { base: 60_000, increment: 2_000 }, 60_000, 60_000);
+            OhMyChess::update_clock(&mut fischer, &Player::White, 11_000).unwrap();
+            assert_eq!((fischer.white_remaining, fischer.black_remaining, fischer.last_move_timestamp), (52_000, 60_000, 11_000));
+        }
+
+        #[test]
+        fn bronstein_delay_gives_back_the_time_used_up_to_the_delay() {
+            let mut bronstein = clock(TimeControl::Bronstein { base: 60_000, delay: 5_000 }, 60_000, 60_000);
+            OhMyChess::update_clock(&mut bronstein, &Player::Black, 4_000).unwrap();
+            assert_eq!(bronstein.black_remaining, 60_000);
+            OhMyChess::update_clock(&mut bronstein, &Player::White, 14_000).unwrap();
+            assert_eq!(bronstein.white_remaining, 55_000);
+        }
+
+        #[test]
+        fn correspondence_clock_resets_every_move() {
+            let mut correspondence = OhMyChess::clock_from_time_control(Some(TimeControl::Correspondence { days_per_move: 3 })).unwrap().unwrap();
+            assert_eq!(correspondence.white_remaining, 3 * MILLISECONDS_PER_DAY);
+
+            let now = correspondence.last_move_timestamp + 2 * MILLISECONDS_PER_DAY;
+            OhMyChess::update_clock(&mut correspondence, &Player::White, now).unwrap();
+            assert_eq!(correspondence.white_remaining, 3 * MILLISECONDS_PER_DAY);
+            assert!(OhMyChess::is_flag_fallen(&correspondence, &Player::Black, now + 3 * MILLISECONDS_PER_DAY));
+        }
+
+        #[test]
+        fn move_is_rejected_once_the_flag_has_fallen() {
+            let mut fischer = clock(TimeControl::Fischer { base: 10_000, increment: 0 }, 10_000, 10_000);
+            assert!(!OhMyChess::is_flag_fallen(&fischer, &Player::White, 10_999));
+            assert!(OhMyChess::is_flag_fallen(&fischer, &Player::White, 11_000));
+            assert_eq!(OhMyChess::update_clock(&mut fischer, &Player::White, 11_000), Err(OutOfTime));
+            assert_eq!(fischer.last_move_timestamp, 1_000);
+        }
+
+        #[ink::test]
+        fn threefold_repetition_is_claimed_by_either_player() {
+            ink::env::test::set_caller::<PinkEnvironment>(AccountId::from([1; 32]));
+            let contract = local_cache_contract();
+            let store = contract.store();
+            let session_id = contract.start_new_game_session(Some(ColourChoice::White), Some([2; 32]), None, false).unwrap();
+            for _ in 0..2 {
+                for (caller, from, to) in [(1, "g1", "f3"), (2, "g8", "f6"), (1, "f3", "g1"), (2, "f6", "g8")] {
+                    assert_eq!(contract.claim_draw(session_id.clone()), Err(NoDrawToClaim));
+                    play_as(&contract, &session_id, caller, from, to).unwrap();
+                }
+            }
+
+            // Black has just moved, white is to move
+            ink::env::test::set_caller::<PinkEnvironment>(AccountId::from([3; 32]));
+            assert!(contract.claim_draw(session_id.clone()).is_err());
+            ink::env::test::set_caller::<PinkEnvironment>(AccountId::from([2; 32]));
+            contract.claim_draw(session_id.clone()).unwrap();
+            assert_eq!(store.find_game_session(&session_id).unwrap().status, GameStatus::Draw);
+        }
+
+        #[ink::test]
+        fn takeback_gives_back_the_time_spent_on_the_moves_taken_back() {
+            ink::env::test::set_block_timestamp::<PinkEnvironment>(1_000);
+            ink::env::test::set_caller::<PinkEnvironment>(AccountId::from([1; 32]));
+            let contract = local_cache_contract();
+            let store = contract.store();
+            let session_id = contract.start_new_game_session(Some(ColourChoice::White), Some([2; 32]), Some(TimeControl::Fischer { base: 60_000, increment: 0 }), false).unwrap();
+
+            ink::env::test::set_block_timestamp::<PinkEnvironment>(11_000);
+            play_as(&contract, &session_id, 1, "e2", "e4").unwrap();
+            ink::env::test::set_block_timestamp::<PinkEnvironment>(31_000);
+            play_as(&contract, &session_id, 2, "e7", "e5").unwrap();
+            contract.request_takeback(session_id.clone()).unwrap();
+            ink::env::test::set_caller::<PinkEnvironment>(AccountId::from([1; 32]));
+            ink::env::test::set_block_timestamp::<PinkEnvironment>(41_000);
+            contract.accept_takeback(session_id.clone()).unwrap();
+
+            let clock = store.find_game_session(&session_id).unwrap().clock.unwrap();
+            assert_eq!((clock.white_remaining, clock.black_remaining, clock.last_move_timestamp), (50_000, 60_000, 41_000));
+        }
+
+        #[ink::test]
+        fn timeout_is_a_draw_for_a_player_who_cannot_checkmate() {
+            ink::env::test::set_caller::<PinkEnvironment>(AccountId::from([2; 32]));
+            let contract = local_cache_contract();
+            let store = contract.store();
+            for (fen, status) in [("4k3/8/8/8/8/8/8/K6Q w - - 0 1", GameStatus::Draw), ("4k3/8/8/8/8/8/8/K2n4 w - - 0 1", GameStatus::Draw), ("4k3/8/8/8/8/5B2/8/K2b4 w - - 0 1", GameStatus::Draw), ("4k3/8/8/8/8/8/P7/K2n4 w - - 0 1", GameStatus::WonByPlayerBlack), ("4k3/8/8/8/8/8/8/K2n3Q w - - 0 1", GameStatus::WonByPlayerBlack), ("4k2r/8/8/8/8/8/8/K6Q w - - 0 1", GameStatus::WonByPlayerBlack)] {
+                let mut lobby = position_from_fen(fen);
+                lobby.players = PlayersAddressesLobby { black: Some([2; 32]), white: Some([1; 32]) };
+                lobby.clock = Some(clock(TimeControl::Fischer { base: 10_000, increment: 0 }, 10_000, 10_000));
+                let session_id = store.insert_game_session(&lobby).unwrap();
+
+                ink::env::test::set_block_timestamp::<PinkEnvironment>(11_000);
+                contract.claim_timeout(session_id.clone()).unwrap();
+                assert_eq!(store.find_game_session(&session_id).unwrap().status, status);
+            }
+        }
+
+        #[test]
+        fn time_control_without_time_is_rejected() {
+            assert_eq!(OhMyChess::clock_from_time_control(None), Ok(None));
+            assert_eq!(OhMyChess::clock_from_time_control(Some(TimeControl::Fischer { base: 0, increment: 1_000 })), Err(NonValidTimeControl));
+            assert_eq!(OhMyChess::clock_from_time_control(Some(TimeControl::Correspondence { days_per_move: 0 })), Err(NonValidTimeControl));
+        }
+
+        #[test]
+        fn lobby_with_an_empty_seat_goes_stale_after_the_ttl() {
+            ink::env::test::set_caller::<PinkEnvironment>(AccountId::from([1; 32]));
+            let contract = new_contract("https://example.com");
+            let mut lobby = position_from_fen(INITIAL_FEN);
+            lobby.players.black = Some([1; 32]);
+            lobby.created_at = 1_000;
+
+            ink::env::test::set_block_timestamp::<PinkEnvironment>(1_000 + DEFAULT_LOBBY_TTL - 1);
+            assert!(!contract.is_lobby_stale(&lobby));
+            ink::env::test::set_block_timestamp::<PinkEnvironment>(1_000 + DEFAULT_LOBBY_TTL);
+            assert!(contract.is_lobby_stale(&lobby));
+
+            // A game with both seats taken never expires as a lobby
+            lobby.players.white = Some([2; 32]);
+            assert!(!contract.is_lobby_stale(&lobby));
+        }
+
+        #[test]
+        fn lobbies_are_read_from_the_find_response() {
+            let body = br#"{"documents":[{"_id":"65f1c0ffee0123456789abcd","players":{"white":null,"black":[1,1,1,1,1,1,1,1,1,1,1,1,1,1,1,1,1,1,1,1,1,1,1,1,1,1,1,1,1,1,1,1]},"clock":null,"rated":false,"created_at":1710000000000,"version":0}]}"#;
+            let (result, _) = serde_json_core::from_slice::<FindMongoDBLobbiesResult>(body).unwrap();
+            assert_eq!(result.documents.len(), 1);
+            assert_eq!(result.documents[0].id, JsonString(String::from("65f1c0ffee0123456789abcd")));
+            assert_eq!(result.documents[0].players.black, Some([1; 32]));
+            assert_eq!(result.documents[0].players.white, None);
+            assert_eq!(result.documents[0].created_at, 1_710_000_000_000);
+        }
+
+        #[ink::test]
+        fn sessions_stored_before_the_later_fields_are_still_played() {
+            #[derive(Serialize)]
+            struct FirstReleaseSession {
+                board: Board,
+                turn: Player,
+                players: PlayersAddressesLobby,
+                status: GameStatus,
+            }
+            let initial_position = position_from_fen(INITIAL_FEN);
+            let first_release_session = FirstReleaseSession {
+                board: initial_position.board,
+                turn: Player::White,
+                players: PlayersAddressesLobby { black: Some([2; 32]), white: Some([1; 32]) },
+                status: GameStatus::Ongoing,
+            };
+            let body = format!(r#"{{"document":{}}}"#, serde_json::to_string(&first_release_session).unwrap());
+            let (result, _) = serde_json_core::from_slice::<FindMongoDBDocumentResult>(body.as_bytes()).unwrap();
+            let game_state_lobby = result.document.unwrap();
+            assert_eq!(game_state_lobby.castling_rights, CastlingRights::default());
+            assert_eq!((game_state_lobby.version, game_state_lobby.fullmove_number, game_state_lobby.rated), (0, 1, false));
+            assert!(game_state_lobby.moves.is_empty() && game_state_lobby.undo_history.is_empty());
+
+            ink::env::test::set_caller::<PinkEnvironment>(AccountId::from([1; 32]));
+            let contract = local_cache_contract();
+            let store = contract.store();
+            let session_id = store.insert_game_session(&game_state_lobby).unwrap();
+            play_as(&contract, &session_id, 1, "e2", "e4").unwrap();
+            play_as(&contract, &session_id, 2, "e7", "e5").unwrap();
+            // Without castling rights the king only steps aside
+            play_as(&contract, &session_id, 1, "g1", "f3").unwrap();
+            play_as(&contract, &session_id, 2, "b8", "c6").unwrap();
+            play_as(&contract, &session_id, 1, "f1", "c4").unwrap();
+            play_as(&contract, &session_id, 2, "g8", "f6").unwrap();
+            assert_eq!(play_as(&contract, &session_id, 1, "e1", "g1"), Err(NonValidMove));
+            assert_eq!(MongoDBStore::version_filter(0), r#"{"$in": [0, null]}"#);
+        }
+
+        #[test]
+        fn open_lobbies_filter_selects_the_free_seat_and_time_control() {
+            let filters = LobbyFilters { colour: Some(Player::Black), time_control: Some(TimeControl::Fischer { base: 180_000, increment: 2_000 }), rated: Some(true) };
+            let filter = MongoDBStore::lobbies_filter(&LobbyQuery::Open { filters, created_after: 42, excluded_player: Some([7; 32]) }).unwrap();
+            assert!(filter.starts_with(r#"{"status": "Ongoing", "created_at": {"$gte": 42}"#));
+            assert!(filter.contains(r#""players.black": null, "players.white": {"$ne": null}"#));
+            assert!(filter.contains(r#""clock.time_control": {"Fischer":{"base":180000,"increment":2000}}"#));
+            assert!(filter.contains(r#""rated": true"#));
+            assert!(filter.contains(r#""$nor": [{"players.white": [7,7,7,7,7,7,7,7,7,7,7,7,7,7,7,7,7,7,7,7,7,7,7,7,7,7,7,7,7,7,7,7]}"#));
+            assert!(filter.ends_with('}'));
+
+            let any_lobby = MongoDBStore::lobbies_filter(&LobbyQuery::Open { filters: LobbyFilters::default(), created_after: 42, excluded_player: None }).unwrap();
+            assert!(any_lobby.contains(r#""$or""#));
+            assert!(!any_lobby.contains("clock") && !any_lobby.contains("rated") && !any_lobby.contains("$nor"));
+        }
 
-                if i < bytes.len() - 1 {
-                    s.push('-').map_err(|_| { WrongPlayerAddressArgument })?; // Separate bytes with a '-'
-                }
-            }
+        #[test]
+        fn stale_lobbies_filter_includes_lobbies_without_creation_time() {
+            let filter = MongoDBStore::lobbies_filter(&LobbyQuery::Stale { created_before: 42 }).unwrap();
+            assert!(filter.contains(r#"{"$or": [{"created_at": {"$lt": 42}}, {"created_at": {"$exists": false}}]}"#));
+            assert!(filter.contains(r#"{"$or": [{"players.white": null}, {"players.black": null}]}"#));
 
-            Ok(s)
+            let body = br#"{"documents":[{"_id":"65f1c0ffee0123456789abcd","players":{"white":[1,1,1,1,1,1,1,1,1,1,1,1,1,1,1,1,1,1,1,1,1,1,1,1,1,1,1,1,1,1,1,1],"black":null}}]}"#;
+            let (result, _) = serde_json_core::from_slice::<FindMongoDBLobbiesResult>(body).unwrap();
+            assert_eq!((result.documents[0].created_at, result.documents[0].version), (0, 0));
         }
 
-        pub fn check_move_boundaries(chess_move: &ChessMove) -> Result<()> {
-            if chess_move.from.0 > 7 || chess_move.from.1 > 7 {
-                return Err(OutOfBoardChessFrom);
-            }
-            if chess_move.to.0 > 7 || chess_move.to.1 > 7 {
-                return Err(OutOfBoardChessTo);
-            }
-            Ok(())
+        #[ink::test]
+        fn lobbies_without_creation_time_are_expired_and_untracked() {
+            ink::env::test::set_caller::<PinkEnvironment>(AccountId::from([1; 32]));
+            let contract = local_cache_contract();
+            let store = contract.store();
+            let mut lobby = position_from_fen(INITIAL_FEN);
+            lobby.players.white = Some([1; 32]);
+            lobby.created_at = 0;
+            let session_id = store.insert_game_session(&lobby).unwrap();
+            store.track_session([1; 32], &session_id).unwrap();
+
+            ink::env::test::set_block_timestamp::<PinkEnvironment>(DEFAULT_LOBBY_TTL + 1);
+            assert_eq!(contract.expire_stale_lobbies(), Ok(1));
+            assert_eq!(store.find_game_session(&session_id).unwrap().status, GameStatus::Expired);
+            assert!(store.find_tracked_sessions([1; 32]).unwrap().is_empty());
         }
 
-        pub fn check_move_validity_for_piece(board: &Board, player: &Player, chess_move: &ChessMove) -> Result<()> {
-            let ChessCell{ piece, .. } = match &board[chess_move.from.0 as usize][chess_move.from.1 as usize] {
-                Some(ref chess_cell) => chess_cell,
-                None => return Err(NoPieceBoardChessFrom), // No piece at source
-            };
+        #[test]
+        fn open_lobby_reports_the_free_seat() {
+            let body = br#"{"documents":[{"_id":"65f1c0ffee0123456789abcd","players":{"white":[1,1,1,1,1,1,1,1,1,1,1,1,1,1,1,1,1,1,1,1,1,1,1,1,1,1,1,1,1,1,1,1],"black":null},"colour_draw":null,"clock":{"time_control":{"Correspondence":{"days_per_move":3}},"white_remaining":259200000,"black_remaining":259200000,"last_move_timestamp":5},"rated":true,"created_at":5,"version":0}]}"#;
+            let (mut result, _) = serde_json_core::from_slice::<FindMongoDBLobbiesResult>(body).unwrap();
+            let open_lobby = OhMyChess::open_lobby_from_document(result.documents.pop().unwrap()).unwrap();
+            assert_eq!(open_lobby, OpenLobby {
+                session_id: String::from("65f1c0ffee0123456789abcd"),
+                open_seat: Player::Black,
+                random_colour: false,
+                time_control: Some(TimeControl::Correspondence { days_per_move: 3 }),
+                rated: true,
+                created_at: 5,
+            });
+        }
 
-            match piece {
-                Piece::Pawn => Self::check_move_validity_pawn(&board, &player, &chess_move),
-                Piece::Knight => Self::check_move_validity_knight(&chess_move),
-                Piece::Bishop => Self::check_move_validity_bishop(&board, &chess_move),
-                Piece::Rook => Self::check_move_validity_rook(&board, &chess_move),
-                Piece::Queen => Self::check_move_validity_queen(&board, &chess_move),
-                Piece::King => Self::check_move_validity_king(&chess_move),
+        #[test]
+        fn random_colours_are_drawn_once_both_seats_are_taken() {
+            for (random_byte, creator_colour) in [(0x42, Player::White), (0x43, Player::Black)] {
+                let mut lobby = position_from_fen(INITIAL_FEN);
+                lobby.colour_draw = OhMyChess::colour_draw_from_choice(Some(ColourChoice::Random));
+                lobby.players.white = Some([1; 32]); // the creator, provisionally white
+                assert!(!OhMyChess::is_colour_draw_pending(&lobby));
+
+                lobby.players.black = Some([2; 32]);
+                assert!(OhMyChess::is_colour_draw_pending(&lobby));
+                OhMyChess::draw_colours(&mut lobby, random_byte);
+
+                let (creator_seat, joiner_seat) = if creator_colour == Player::White { (lobby.players.white, lobby.players.black) } else { (lobby.players.black, lobby.players.white) };
+                assert_eq!((creator_seat, joiner_seat), (Some([1; 32]), Some([2; 32])));
+                assert_eq!(lobby.colour_draw, Some(ColourDraw { random_byte: Some(random_byte), creator_colour: Some(creator_colour) }));
+                // The draw is settled for good
+                assert!(!OhMyChess::is_colour_draw_pending(&lobby));
             }
         }
 
-        // Helper function to find the king's position for the given player
-        pub fn find_king_position(board: &Board, player: &Player) -> Result<(u8, u8)> {
-            for x in 0..8 {
-                for y in 0..8 {
-                    if let Some(ChessCell { piece: Piece::King, player: cell_player }) = board[x][y] {
-                        if cell_player == *player {
-                            return Ok((x as u8, y as u8));
-                        }
-                    }
-                }
-            }
+        #[test]
+        fn chosen_colour_needs_no_draw() {
+            let mut lobby = position_from_fen(INITIAL_FEN);
+            lobby.colour_draw = OhMyChess::colour_draw_from_choice(Some(ColourChoice::Black));
+            lobby.players = PlayersAddressesLobby { white: Some([2; 32]), black: Some([1; 32]) };
+            assert!(!OhMyChess::is_colour_draw_pending(&lobby));
+        }
 
-            Err(CouldNotFindKingPiece) // It should be impossible to not find the king
+        fn rating(rating: i64, deviation: i64, volatility: Fixed) -> PlayerRating {
+            PlayerRating { rating: rating * FIXED_ONE as i64, deviation: deviation * FIXED_ONE as i64, volatility: volatility as i64, ..PlayerRating::default() }
         }
 
-        pub fn is_check_mate(board: &Board, player: &Player) -> Result<bool> {
-            let (king_x, king_y) = Self::find_king_position(board, player)?;
+        fn assert_close(actual: i64, expected: f64, tolerance: f64) {
+            let actual = actual as f64 / FIXED_ONE as f64;
+            assert!((actual - expected).abs() <= tolerance, "{} is not {}", actual, expected);
+        }
 
-            // For each cell on the board, check if it's an opponent's piece that can threaten the king
-            for x in 0..8 {
-                for y in 0..8 {
-                    if let Some(ChessCell { piece: _, player: cell_player }) = board[x][y] {
-                        if cell_player != (*player) {
-                            let threat_move = ChessMove { from: (x as u8, y as u8), to: (king_x, king_y) };
-                            // Check if this opponent's piece can move to capture the king
-                            if Self::check_move_validity_for_piece(&board, &cell_player, &threat_move).is_ok() {
-                                // If this piece can threaten the king, check for a response
-                                if !Self::has_response_to_threat(&board, &player, (x, y))? {
-                                    return Ok(true);
-                                }
-                            }
-                        }
-                    }
-                }
+        #[test]
+        fn fixed_point_functions_are_accurate() {
+            for (x, expected_exp) in [(0, 1.0), (FIXED_ONE, core::f64::consts::E), (-5 * FIXED_ONE, 0.006737947), (3_500_000_000, 33.11545196)] {
+                assert_close(OhMyChess::fixed_exp(x) as i64, expected_exp, 1e-6 * expected_exp.max(1.0));
             }
-
-            // If we've checked all pieces and there is always response to threat, the player can escape from check
-            Ok(false)
+            for (x, expected_ln) in [(FIXED_ONE, 0.0), (3_600_000, -5.626821434), (2_718_281_828, 1.0), (123 * FIXED_ONE, 4.812184355)] {
+                assert_close(OhMyChess::fixed_ln(x) as i64, expected_ln, 1e-6);
+            }
+            assert_close(OhMyChess::fixed_sqrt(2 * FIXED_ONE) as i64, core::f64::consts::SQRT_2, 1e-9);
         }
 
-        fn has_response_to_threat(board: &Board, player: &Player, threat_position: (usize, usize)) -> Result<bool> {
-            let (king_x, king_y) = Self::find_king_position(board, player)?;
-            let (threat_x, threat_y) = threat_position;
+        #[test]
+        fn glicko2_matches_glickman_example() {
+            // Player rated 1500 (RD 200) beating a 1400 (RD 30) then losing to 1550 (RD 100) and 1700 (RD 300)
+            let player = rating(1500, 200, 60_000_000);
+            let games = [
+                (rating(1400, 30, 60_000_000), FIXED_ONE),
+                (rating(1550, 100, 60_000_000), 0),
+                (rating(1700, 300, 60_000_000), 0),
+            ];
+            let updated = OhMyChess::glicko2_update(&player, &games);
+            assert_close(updated.rating, 1464.06, 0.01);
+            assert_close(updated.deviation, 151.52, 0.01);
+            assert_close(updated.volatility, 0.05999, 0.00001);
+            assert_eq!(updated.games, 3);
+        }
 
-            // Calculate direction of the threat
-            let dx = (king_x as i32 - threat_x as i32).signum();
-            let dy = (king_y as i32 - threat_y as i32).signum();
+        #[test]
+        fn rated_game_moves_both_ratings_in_opposite_directions() {
+            let (white, black) = (PlayerRating::default(), PlayerRating::default());
+            let white_score = OhMyChess::white_score(&GameStatus::WonByPlayerWhite).unwrap();
+            let new_white = OhMyChess::glicko2_update(&white, &[(black.clone(), white_score)]);
+            let new_black = OhMyChess::glicko2_update(&black, &[(white, FIXED_ONE - white_score)]);
+
+            assert!(new_white.rating > 1500 * FIXED_ONE as i64 && new_black.rating < 1500 * FIXED_ONE as i64);
+            assert_eq!(new_white.rating - 1500 * FIXED_ONE as i64, 1500 * FIXED_ONE as i64 - new_black.rating);
+            assert!(new_white.deviation < 350 * FIXED_ONE as i64);
+            assert_eq!(OhMyChess::white_score(&GameStatus::Stalemate), Some(FIXED_ONE / 2));
+            assert_eq!(OhMyChess::white_score(&GameStatus::Ongoing), None);
+        }
 
-            let mut check_x = threat_x as i32 + dx;
-            let mut check_y = threat_y as i32 + dy;
+        fn play_as(contract: &OhMyChess, session_id: &str, caller: u8, from: &str, to: &str) -> Result<()> {
+            ink::env::test::set_caller::<PinkEnvironment>(AccountId::from([caller; 32]));
+            contract.make_move(ChessMove { from: square(from), to: square(to), promotion: None }, String::from(session_id))
+        }
 
-            // Iterate through the path from the threat to the king
-            while (check_x != king_x as i32 || check_y != king_y as i32) && (check_x >= 0 && check_x < 8 && check_y >= 0 && check_y < 8) {
-                // For each player's piece, check if moving it to (check_x, check_y) blocks the threat
-                for x in 0..8 {
-                    for y in 0..8 {
-                        if let Some(ChessCell { player: cell_player, .. }) = board[x][y] {
-                            if cell_player == *player {
-                                let potential_block_move = ChessMove { from: (x as u8, y as u8), to: (check_x as u8, check_y as u8) };
-                                if Self::check_move_validity_for_piece(&board, &player, &potential_block_move).is_ok() {
-                                    let mut temp_board = board.clone();
-                                    Self::update_board_from_move(&mut temp_board, &player, &potential_block_move)?;
-                                    if !Self::is_in_check(&temp_board, &player)? {
-                                        return Ok(true);
-                                    }
-                                }
-                            }
-                        }
-                    }
-                }
+        #[test]
+        fn local_cache_session_goes_from_creation_to_mate() {
+            ink::env::test::set_caller::<PinkEnvironment>(AccountId::from([1; 32]));
+            let contract = local_cache_contract();
+            let store = contract.store();
+            let session_id = contract.start_new_game_session(Some(ColourChoice::White), None, None, false).unwrap();
+            assert_eq!(play_as(&contract, &session_id, 1, "f2", "f3"), Err(SessionNeedsSecondPlayer));
+
+            ink::env::test::set_caller::<PinkEnvironment>(AccountId::from([2; 32]));
+            contract.join_session(session_id.clone()).unwrap();
+            assert_eq!(contract.join_session(session_id.clone()), Err(AlreadyInThisGameSession));
+
+            // Fool's mate
+            play_as(&contract, &session_id, 1, "f2", "f3").unwrap();
+            assert_eq!(play_as(&contract, &session_id, 1, "e2", "e4"), Err(NotYourTurn));
+            play_as(&contract, &session_id, 2, "e7", "e5").unwrap();
+            play_as(&contract, &session_id, 1, "g2", "g4").unwrap();
+            play_as(&contract, &session_id, 2, "d8", "h4").unwrap();
+
+            let game_state_lobby = store.find_game_session(&session_id).unwrap();
+            assert_eq!(game_state_lobby.status, GameStatus::WonByPlayerBlack);
+            assert_eq!(game_state_lobby.moves.len(), 4);
+            assert_eq!(play_as(&contract, &session_id, 1, "e2", "e4"), Err(GameSessionIsOver));
+            assert_eq!(store.find_tracked_sessions([1; 32]).unwrap(), [session_id.as_str()]);
+            assert_eq!(store.find_tracked_sessions([2; 32]).unwrap(), [session_id]);
+        }
 
-                check_x += dx;
-                check_y += dy;
-            }
+        #[test]
+        fn colours_of_a_random_colour_invitation_are_drawn_when_the_invited_player_accepts() {
+            ink::env::test::set_caller::<PinkEnvironment>(AccountId::from([1; 32]));
+            let contract = local_cache_contract();
+            let store = contract.store();
+            let session_id = contract.start_new_game_session(Some(ColourChoice::Random), Some([2; 32]), None, false).unwrap();
+
+            // Nothing is drawn in the transaction of the creator, nor can the game start before the invited player accepts
+            let game_state_lobby = store.find_game_session(&session_id).unwrap();
+            assert_eq!(game_state_lobby.colour_draw, Some(ColourDraw { random_byte: None, creator_colour: None }));
+            assert_eq!(play_as(&contract, &session_id, 1, "e2", "e4"), Err(SessionNeedsSecondPlayer));
+            assert_eq!(contract.join_session(session_id.clone()), Err(AlreadyInThisGameSession));
+
+            // An odd byte makes the creator of a random colour session play black
+            pink::chain_extension::mock::mock_getrandom(|length| alloc::vec![1; length as usize]);
+            ink::env::test::set_caller::<PinkEnvironment>(AccountId::from([2; 32]));
+            contract.join_session(session_id.clone()).unwrap();
+            assert_eq!(contract.join_session(session_id.clone()), Err(AlreadyInThisGameSession));
+
+            let game_state_lobby = store.find_game_session(&session_id).unwrap();
+            assert_eq!(game_state_lobby.players, PlayersAddressesLobby { white: Some([2; 32]), black: Some([1; 32]) });
+            assert_eq!(game_state_lobby.colour_draw, Some(ColourDraw { random_byte: Some(1), creator_colour: Some(Player::Black) }));
+            play_as(&contract, &session_id, 2, "e2", "e4").unwrap();
+            assert_eq!(store.find_tracked_sessions([2; 32]).unwrap(), [session_id]);
+        }
 
-            Ok(false)
+        // Player 1 plays white and player 2 black, from the given position
+        fn session_from_fen(contract: &OhMyChess, fen: &str) -> String {
+            ink::env::test::set_caller::<PinkEnvironment>(AccountId::from([1; 32]));
+            contract.start_new_game_session_from_fen(String::from(fen), Some(ColourChoice::White), Some([2; 32]), None).unwrap()
         }
 
-        pub fn is_in_check(board: &Board, player: &Player) -> Result<bool> {
-            let king_position = Self::find_king_position(&board, &player)?;
-            for x in 0..8 {
-                for y in 0..8 {
-                    if let Some(ChessCell { piece: _, player: cell_player }) = board[x][y] {
-                        if cell_player != *player {
-                            // Temporarily make a move
-                            let chess_move = ChessMove { from: (x as u8, y as u8), to: king_position };
-                            if Self::check_move_validity_for_piece(board, &player, &chess_move).is_ok() {
-                                // If any opponent's piece can move to the king's position, the player is in check
-                                return Ok(true);
-                            }
-                        }
+        #[test]
+        fn castling_out_of_through_or_into_check_is_rejected() {
+            ink::env::test::set_caller::<PinkEnvironment>(AccountId::from([1; 32]));
+            let contract = local_cache_contract();
+            for (fen, king_side, queen_side) in [
+                ("4k3/8/8/8/8/8/8/R3K2R w KQ - 0 1", true, true),
+                ("4r1k1/8/8/8/8/8/8/R3K2R w KQ - 0 1", false, false), // In check
+                ("5rk1/8/8/8/8/8/8/R3K2R w KQ - 0 1", false, true), // Through f1
+                ("3r2k1/8/8/8/8/8/8/R3K2R w KQ - 0 1", true, false), // Through d1
+                ("6rk/7p/8/8/8/8/8/R3K2R w KQ - 0 1", false, true), // Into check on g1
+                ("1r4k1/8/8/8/8/8/8/R3K2R w KQ - 0 1", true, true), // Only the rook crosses b1
+            ] {
+                for (to, allowed, castled_fen) in [("g1", king_side, "R4RK1 b - - 1 1"), ("c1", queen_side, "2KR3R b - - 1 1")] {
+                    let session_id = session_from_fen(&contract, fen);
+                    if allowed {
+                        play_as(&contract, &session_id, 1, "e1", to).unwrap();
+                        assert!(contract.get_fen(session_id).unwrap().ends_with(castled_fen), "{} {}", fen, to);
+                    } else {
+                        assert_eq!(play_as(&contract, &session_id, 1, "e1", to), Err(NonValidMove), "{} {}", fen, to);
                     }
                 }
             }
-            Ok(false)
         }
 
-        pub fn check_move_validity_pawn(board: &Board, player: &Player, chess_move: &ChessMove) -> Result<()> {
-            let (fx, fy) = chess_move.from;
-            let (tx, ty) = chess_move.to;
-
-            let forward = match player {
-                Player::White => 1,
-                Player::Black => -1,
-            };
+        #[test]
+        fn en_passant_is_only_possible_on_the_next_ply() {
+            ink::env::test::set_caller::<PinkEnvironment>(AccountId::from([1; 32]));
+            let contract = local_cache_contract();
+            let fen = "4k3/3p4/8/4P3/8/8/8/4K3 b - - 0 1";
+
+            let session_id = session_from_fen(&contract, fen);
+            play_as(&contract, &session_id, 2, "d7", "d5").unwrap();
+            play_as(&contract, &session_id, 1, "e5", "d6").unwrap();
+            assert_eq!(contract.get_fen(session_id).unwrap(), "4k3/8/3P4/8/8/8/8/4K3 b - - 0 2");
+
+            // A single move in between lets the right lapse
+            let session_id = session_from_fen(&contract, fen);
+            play_as(&contract, &session_id, 2, "d7", "d5").unwrap();
+            play_as(&contract, &session_id, 1, "e1", "e2").unwrap();
+            play_as(&contract, &session_id, 2, "e8", "f7").unwrap();
+            assert_eq!(play_as(&contract, &session_id, 1, "e5", "d6"), Err(NonValidMove));
+            assert_eq!(contract.get_fen(session_id).unwrap(), "8/5k2/8/3pP3/8/8/4K3/8 w - - 2 3");
+        }
 
-            // Check forward move of 1 step
-            if fx as i32 + forward == tx as i32 && fy == ty && board[tx as usize][ty as usize].is_none() {
-                return Ok(());
+        #[test]
+        fn pawns_promote_to_the_chosen_piece_or_a_queen_by_default() {
+            ink::env::test::set_caller::<PinkEnvironment>(AccountId::from([1; 32]));
+            let contract = local_cache_contract();
+            for (promotion, promoted_fen) in [
+                (Some(Piece::Queen), "Q7/8/8/8/8/8/8/k3K3 b - - 0 1"),
+                (Some(Piece::Rook), "R7/8/8/8/8/8/8/k3K3 b - - 0 1"),
+                (Some(Piece::Bishop), "B7/8/8/8/8/8/8/k3K3 b - - 0 1"),
+                (Some(Piece::Knight), "N7/8/8/8/8/8/8/k3K3 b - - 0 1"),
+                (None, "Q7/8/8/8/8/8/8/k3K3 b - - 0 1"),
+            ] {
+                let session_id = session_from_fen(&contract, "8/P7/8/8/8/8/8/k3K3 w - - 0 1");
+                contract.make_move(ChessMove { from: square("a7"), to: square("a8"), promotion }, session_id.clone()).unwrap();
+                assert_eq!(contract.get_fen(session_id).unwrap(), promoted_fen);
             }
 
-            // Check forward move of 2 steps
-            if ((player == &Player::White && fx == 1) || (player == &Player::Black && fx == 6))
-                && tx as i32 == fx as i32 + 2 * forward && fy == ty && board[tx as usize][ty as usize].is_none()
-                && board[(fx as i32 + forward) as usize][fy as usize].is_none() {
-                // Check if the path is clear
-                return Ok(());
+            // A pawn cannot stay a pawn nor become a king, and only a pawn reaching the last rank promotes
+            for (from, to, promotion) in [("a7", "a8", Piece::Pawn), ("a7", "a8", Piece::King), ("e1", "e2", Piece::Queen)] {
+                let session_id = session_from_fen(&contract, "8/P7/8/8/8/8/8/k3K3 w - - 0 1");
+                let chess_move = ChessMove { from: square(from), to: square(to), promotion: Some(promotion) };
+                assert_eq!(contract.make_move(chess_move, session_id), Err(NonValidPromotion));
             }
+        }
 
-            // Check capture move
-            if fx as i32 + forward == tx as i32 && (fy as i32 - 1 == ty as i32 || fy as i32 + 1 == ty as i32) {
-                if let Some(ChessCell{player: piece_player, ..}) = board[tx as usize][ty as usize] {
-                    if *player != piece_player {
-                        // Capture if it's an opponent's piece
-                        return Ok(())
-                    }
-                }
-            }
+        #[test]
+        fn local_cache_lobbies_are_listed_matched_and_cancelled() {
+            ink::env::test::set_caller::<PinkEnvironment>(AccountId::from([1; 32]));
+            let contract = local_cache_contract();
+            let store = contract.store();
+            let time_control = TimeControl::Fischer { base: 180_000, increment: 2_000 };
+            let session_id = contract.start_new_game_session(Some(ColourChoice::White), None, Some(time_control), false).unwrap();
+            let other_session_id = contract.start_new_game_session(Some(ColourChoice::Black), None, None, false).unwrap();
+
+            let blitz = LobbyFilters { colour: Some(Player::Black), time_control: Some(time_control), rated: None };
+            let open_lobbies = contract.list_open_lobbies(0, blitz.clone()).unwrap();
+            assert_eq!(open_lobbies.len(), 1);
+            assert_eq!((open_lobbies[0].session_id.as_str(), open_lobbies[0].open_seat), (session_id.as_str(), Player::Black));
+            assert_eq!(contract.list_open_lobbies(0, LobbyFilters::default()).unwrap().len(), 2);
+            assert!(contract.list_open_lobbies(1, LobbyFilters::default()).unwrap().is_empty());
+
+            // The creator is never matched against their own lobby
+            assert_ne!(contract.quick_match(blitz.clone()).unwrap(), session_id);
+
+            ink::env::test::set_caller::<PinkEnvironment>(AccountId::from([3; 32]));
+            assert_eq!(contract.quick_match(blitz).unwrap(), session_id);
+            assert_eq!(contract.cancel_session(other_session_id.clone()), Err(NotInThisGameSession));
+
+            ink::env::test::set_caller::<PinkEnvironment>(AccountId::from([1; 32]));
+            contract.cancel_session(other_session_id.clone()).unwrap();
+            assert_eq!(store.find_game_session(&other_session_id).unwrap().status, GameStatus::Cancelled);
+            assert!(!store.find_tracked_sessions([1; 32]).unwrap().contains(&other_session_id));
+        }
 
-            Err(NonValidMove)
+        #[test]
+        fn local_cache_updates_from_a_stale_read_are_rejected() {
+            ink::env::test::set_caller::<PinkEnvironment>(AccountId::from([1; 32]));
+            let contract = local_cache_contract();
+            let store = contract.store();
+            let session_id = contract.start_new_game_session(Some(ColourChoice::White), None, None, false).unwrap();
+
+            // Two players read the lobby with its free seat, only the first one to write takes it
+            let stale_lobby = store.find_game_session(&session_id).unwrap();
+            ink::env::test::set_caller::<PinkEnvironment>(AccountId::from([2; 32]));
+            contract.join_session(session_id.clone()).unwrap();
+            let stale_join = OhMyChess::game_state_from_lobby(stale_lobby.clone(), PlayersAddresses { white: [1; 32], black: [3; 32] });
+            assert_eq!(OhMyChess::save_game_session(&*store, stale_join, &session_id), Err(ConcurrentModification));
+            assert_eq!(store.find_game_session(&session_id).unwrap().players.black, Some([2; 32]));
+            assert_eq!(store.update_game_session_status(&session_id, stale_lobby.version, &GameStatus::Cancelled), Err(ConcurrentModification));
+
+            // Two moves validated against the same position
+            let game_state = OhMyChess::find_started_game_session(&*store, &session_id).unwrap();
+            play_as(&contract, &session_id, 1, "e2", "e4").unwrap();
+            assert_eq!(OhMyChess::save_game_session(&*store, game_state, &session_id), Err(ConcurrentModification));
+            let game_state_lobby = store.find_game_session(&session_id).unwrap();
+            assert_eq!((game_state_lobby.moves.len(), game_state_lobby.version), (1, 2));
         }
 
-        pub fn check_move_validity_knight(chess_move: &ChessMove) -> Result<()> {
-            let (fx, fy, tx, ty) = (chess_move.from.0, chess_move.from.1, chess_move.to.0, chess_move.to.1);
-            let dx = (fx as i32 - tx as i32).abs();
-            let dy = (fy as i32 - ty as i32).abs();
+        #[test]
+        fn local_cache_rated_resignation_updates_both_ratings() {
+            ink::env::test::set_caller::<PinkEnvironment>(AccountId::from([1; 32]));
+            let contract = local_cache_contract();
+            let session_id = contract.start_new_game_session(Some(ColourChoice::White), Some([2; 32]), None, true).unwrap();
 
-            // Check L-shape move
-            if (dx == 2 && dy == 1) || (dx == 1 && dy == 2) { Ok(()) } else { Err(NonValidMove) }
+            ink::env::test::set_caller::<PinkEnvironment>(AccountId::from([2; 32]));
+            contract.resign(session_id.clone()).unwrap();
+
+            let (white_rating, black_rating) = (contract.get_rating([1; 32]).unwrap(), contract.get_rating([2; 32]).unwrap());
+            assert!(white_rating.rating > PlayerRating::default().rating && black_rating.rating < PlayerRating::default().rating);
+            assert_eq!((white_rating.games, black_rating.games), (1, 1));
         }
 
-        pub fn check_move_validity_bishop(board: &Board, chess_move: &ChessMove) -> Result<()> {
-            // Bishop can move diagonally
-            let is_diagonal = (chess_move.from.0 as i32 - chess_move.to.0 as i32).abs() == (chess_move.from.1 as i32 - chess_move.to.1 as i32).abs();
+        #[ink::test]
+        fn local_cache_ratings_are_applied_once_and_never_from_a_stale_read() {
+            ink::env::test::set_caller::<PinkEnvironment>(AccountId::from([1; 32]));
+            let contract = local_cache_contract();
+            let store = contract.store();
+            let session_id = contract.start_new_game_session(Some(ColourChoice::White), Some([2; 32]), None, true).unwrap();
+            ink::env::test::set_caller::<PinkEnvironment>(AccountId::from([2; 32]));
+            contract.resign(session_id.clone()).unwrap();
+            let black_rating = contract.get_rating([2; 32]).unwrap();
+
+            // The session failed to record the update of black, which did reach its rating
+            let mut game_state = OhMyChess::find_started_game_session(&*store, &session_id).unwrap();
+            let mut ratings_update = game_state.ratings_update.clone().unwrap();
+            assert!(ratings_update.white_applied && ratings_update.black_applied);
+            ratings_update.black_applied = false;
+            game_state.ratings_update = Some(ratings_update);
+            OhMyChess::commit_game_session(&*store, &mut game_state, &session_id).unwrap();
+
+            assert_eq!(contract.apply_ratings(session_id.clone()), Ok(()));
+            assert_eq!(contract.get_rating([2; 32]), Ok(black_rating.clone()));
+            let game_state = OhMyChess::find_started_game_session(&*store, &session_id).unwrap();
+            assert!(game_state.ratings_update.unwrap().black_applied);
+
+            assert_eq!(store.update_player_rating([2; 32], 0, &PlayerRating::default()), Err(ConcurrentModification));
+            assert_eq!(contract.get_rating([2; 32]), Ok(black_rating));
+        }
 
-            if is_diagonal {
-                // Diagonal move: Ensure the path is clear
-                if Self::is_path_clear(&board, chess_move) { Ok(()) }
-                else { Err(NonValidMove) }
-            } else { Err(NonValidMove) }
+        // In-process fake of the MongoDB Atlas Data API endpoints the contract calls, backed by an InMemoryStore
+        #[derive(Deserialize)]
+        struct DataApiNamespace<'a> {
+            collection: &'a str,
+            database: &'a str,
+            #[serde(rename = "dataSource")]
+            data_source: &'a str,
         }
 
-        pub fn check_move_validity_rook(board: &Board, chess_move: &ChessMove) -> Result<()> {
-            // Rook can move horizontally or vertically
-            let is_horizontal = chess_move.from.0 == chess_move.to.0;
-            let is_vertical = chess_move.from.1 == chess_move.to.1;
+        #[derive(Deserialize)]
+        struct ObjectId<'a> {
+            #[serde(rename = "$oid")]
+            oid: &'a str,
+        }
 
-            if is_horizontal {
-                // Horizontal move: Ensure the path is clear
-                if Self::is_path_clear(&board, chess_move) { Ok(()) }
-                else { Err(NonValidMove) }
-            } else if is_vertical {
-                // Vertical move: Ensure the path is clear
-                if Self::is_path_clear(&board, chess_move) { Ok(()) }
-                else { Err(NonValidMove) }
-            } else {
-                Err(NonValidMove)
-            }
+        #[derive(Deserialize)]
+        struct SessionFilter<'a> {
+            #[serde(rename = "_id", borrow)]
+            id: ObjectId<'a>,
+            version: Option<u32>,
         }
 
-        pub fn check_move_validity_king(chess_move: &ChessMove) -> Result<()> {
-            // Calculate the difference in the move for both axes
-            let delta_row = (chess_move.from.0 as i8 - chess_move.to.0 as i8).abs();
-            let delta_col = (chess_move.from.1 as i8 - chess_move.to.1 as i8).abs();
+        #[derive(Deserialize)]
+        struct AddressFilter<'a> {
+            #[serde(rename = "_id")]
+            id: &'a str,
+            version: Option<u32>,
+        }
 
-            if delta_row <= 1 && delta_col <= 1 { Ok(()) } else { Err(NonValidMove) }
+        #[derive(Deserialize)]
+        struct InsertOne<D> {
+            document: D,
         }
 
-        pub fn check_move_validity_queen(board: &Board, chess_move: &ChessMove) -> Result<()> {
-            // Queen can move horizontally, vertically, or diagonally
-            let from = chess_move.from;
-            let to = chess_move.to;
-            let is_horizontal = from.0 == to.0;
-            let is_vertical = from.1 == to.1;
-            let is_diagonal = (from.0 as i32 - to.0 as i32).abs() == (from.1 as i32 - to.1 as i32).abs();
+        #[derive(Deserialize)]
+        struct FindOne<F> {
+            filter: F,
+        }
 
-            if is_horizontal {
-                if Self::is_path_clear(&board, chess_move) { Ok(()) }
-                else { Err(NonValidMove) }
-            } else if is_vertical {
-                if Self::is_path_clear(&board, chess_move) { Ok(()) }
-                else { Err(NonValidMove) }
-            } else if is_diagonal {
-                if Self::is_path_clear(&board, chess_move) { Ok(()) }
-                else { Err(NonValidMove) }
-            } else {
-                Err(NonValidMove)
-            }
+        #[derive(Deserialize)]
+        struct UpdateOne<F, U> {
+            filter: F,
+            update: U,
         }
 
-        fn is_path_clear(board: &[[Option<ChessCell>; 8]; 8], chess_move: &ChessMove) -> bool {
-            let from = (chess_move.from.0 as i16, chess_move.from.1 as i16);
-            let to = (chess_move.to.0 as i16, chess_move.to.1 as i16);
-            let (dx, dy) = (to.0 as i32 - from.0 as i32, to.1 as i32 - from.1 as i32);
-            let step_x = dx.signum() as i16;
-            let step_y = dy.signum() as i16;
+        #[derive(Deserialize)]
+        struct Set<T> {
+            #[serde(rename = "$set")]
+            set: T,
+        }
 
-            let mut current_x = from.0;
-            let mut current_y = from.1;
+        #[derive(Deserialize)]
+        struct StatusUpdate {
+            status: GameStatus,
+        }
 
-            while (current_x, current_y) != (to.0, to.1) {
-                current_x += step_x;
-                current_y += step_y;
+        #[derive(Deserialize)]
+        struct TrackerUpdate<'a> {
+            #[serde(rename = "$push", borrow)]
+            push: Option<TrackedSession<'a>>,
+            #[serde(rename = "$pull", borrow)]
+            pull: Option<TrackedSession<'a>>,
+        }
 
-                // Avoid checking the destination square for a piece
-                if (current_x, current_y) == (to.0, to.1) {
-                    break;
-                }
+        #[derive(Deserialize)]
+        struct TrackedSession<'a> {
+            sessions: &'a str,
+        }
 
-                // Check if the path is clear
-                if board[current_x as usize][current_y as usize].is_some() {
-                    return false;
-                }
+        fn address_from_hex_string(hex_string: &str) -> [u8; 32] {
+            let mut address = [0; 32];
+            for (byte, hex_byte) in address.iter_mut().zip(hex_string.split('-')) {
+                *byte = u8::from_str_radix(hex_byte, 16).unwrap();
             }
-            true
+            address
         }
-    }
 
-    #[derive(Encode, Decode, Deserialize, Serialize, Clone, Debug, PartialEq, TypeInfo)]
-    pub enum Direction {
-        Horizontal,
-        Vertical,
-        Diagonal,
-    }
+        fn json_document<T: Serialize>(document: Option<T>) -> HttpResponse {
+            let json_document = match document {
+                Some(document) => serde_json::to_string(&document).unwrap(),
+                None => String::from("null"),
+            };
+            HttpResponse::ok(format!(r#"{{"document":{}}}"#, json_document).into_bytes())
+        }
 
-    #[derive(Encode, Decode, Deserialize, Serialize, Copy, Clone, Debug, PartialEq, TypeInfo)]
-    pub enum Piece {
-        Pawn, Knight, Bishop, Rook, Queen, King
-    }
+        fn handle_data_api_request(store: &InMemoryStore, request: HttpRequest) -> HttpResponse {
+            let action = request.url.rsplit('/').next().unwrap();
+            // Version 0 also matches the documents without any version, which the store has not
+            let body = String::from_utf8(request.body).unwrap().replace(r#"{"$in": [0, null]}"#, "0");
+            let body = body.as_bytes();
+            let (DataApiNamespace { collection, database, data_source }, _) = serde_json_core::from_slice(body).unwrap();
+            assert_eq!((database, data_source), (DATABASE, DATA_SOURCE));
+
+            match (collection, action) {
+                (GAME_SESSIONS, "insertOne") => {
+                    let (InsertOne { document }, _) = serde_json_core::from_slice::<InsertOne<GameStateLobby>>(body).unwrap();
+                    let session_id = store.insert_game_session(&document).unwrap();
+                    HttpResponse::ok(format!(r#"{{"insertedId":"{}"}}"#, session_id).into_bytes())
+                },
+                (GAME_SESSIONS, "findOne") => {
+                    let (FindOne { filter }, _) = serde_json_core::from_slice::<FindOne<SessionFilter>>(body).unwrap();
+                    json_document(store.find_game_session(filter.id.oid).ok())
+                },
+                (GAME_SESSIONS, "updateOne") => {
+                    // Either the whole game state or its status alone, on the condition of the filtered version
+                    let updated = match serde_json_core::from_slice::<UpdateOne<SessionFilter, Set<GameState>>>(body) {
+                        Ok((UpdateOne { filter, update }, _)) => store.update_game_session(filter.id.oid, filter.version.unwrap(), &(update.set)),
+                        Err(_) => {
+                            let (UpdateOne { filter, update }, _) = serde_json_core::from_slice::<UpdateOne<SessionFilter, Set<StatusUpdate>>>(body).unwrap();
+                            store.update_game_session_status(filter.id.oid, filter.version.unwrap(), &(update.set.status))
+                        },
+                    };
+                    let matched_count = if updated.is_ok() { 1 } else { 0 };
+                    HttpResponse::ok(format!(r#"{{"matchedCount":{},"modifiedCount":{}}}"#, matched_count, matched_count).into_bytes())
+                },
+                (PLAYERS_SESSIONS, "findOne") => {
+                    let (FindOne { filter }, _) = serde_json_core::from_slice::<FindOne<AddressFilter>>(body).unwrap();
+                    let sessions = store.find_tracked_sessions(address_from_hex_string(filter.id)).unwrap();
+                    let sessions: Vec<String> = sessions.iter().map(|session| format!(r#""{}""#, session)).collect();
+                    let document = if sessions.is_empty() { String::from("null") } else { format!(r#"{{"sessions":[{}]}}"#, sessions.join(",")) };
+                    HttpResponse::ok(format!(r#"{{"document":{}}}"#, document).into_bytes())
+                },
+                (PLAYERS_SESSIONS, "updateOne") => {
+                    let (UpdateOne { filter, update }, _) = serde_json_core::from_slice::<UpdateOne<AddressFilter, TrackerUpdate>>(body).unwrap();
+                    let player_address = address_from_hex_string(filter.id);
+                    if let Some(tracked_session) = update.push {
+                        store.track_session(player_address, tracked_session.sessions).unwrap();
+                    }
+                    if let Some(tracked_session) = update.pull {
+                        store.untrack_session(player_address, tracked_session.sessions).unwrap();
+                    }
+                    HttpResponse::ok(Vec::from(&br#"{"matchedCount":1,"modifiedCount":1}"#[..]))
+                },
+                (PLAYER_RATINGS, "findOne") => {
+                    let (FindOne { filter }, _) = serde_json_core::from_slice::<FindOne<AddressFilter>>(body).unwrap();
+                    let player_address = address_from_hex_string(filter.id);
+                    // The store hands out the default rating, the Data API has no document yet
+                    let player_rating = store.find_player_rating(player_address).unwrap();
+                    json_document(Some(player_rating).filter(|player_rating| player_rating.version > 0))
+                },
+                (PLAYER_RATINGS, "updateOne") => {
+                    let (UpdateOne { filter, update }, _) = serde_json_core::from_slice::<UpdateOne<AddressFilter, Set<PlayerRating>>>(body).unwrap();
+                    let result = match store.update_player_rating(address_from_hex_string(filter.id), filter.version.unwrap(), &(update.set)) {
+                        Ok(()) if filter.version == Some(0) => &br#"{"matchedCount":0,"modifiedCount":0,"upsertedId":"65f1c0ffee0123456789abcd"}"#[..],
+                        Ok(()) => &br#"{"matchedCount":1,"modifiedCount":1}"#[..],
+                        Err(_) => &br#"{"matchedCount":0,"modifiedCount":0}"#[..],
+                    };
+                    HttpResponse::ok(Vec::from(result))
+                },
+                _ => HttpResponse::not_found(),
+            }
+        }
 
-    #[derive(Encode, Decode, Deserialize, Serialize, Copy, Clone, Debug, PartialEq, TypeInfo)]
-    pub enum Player {
-        Black, White
-    }
+        // Routes every http request of the contract to a fake Data API, returns the store behind it
+        fn mock_data_api() -> Rc<InMemoryStore> {
+            let store = Rc::new(InMemoryStore::new());
+            let fake_store = store.clone();
+            pink::chain_extension::mock::mock_http_request(move |request| handle_data_api_request(&fake_store, request));
+            store
+        }
 
-    #[derive(Encode, Decode, Deserialize, Serialize, Clone, Debug, PartialEq, TypeInfo)]
-    pub struct PlayersAddresses {
-        black: [u8; 32],
-        white: [u8; 32],
-    }
+        fn play_through_data_api(contract: &OhMyChess, session_id: &str, caller: u8, from: &str, to: &str) -> Result<()> {
+            ink::env::test::set_caller::<PinkEnvironment>(AccountId::from([caller; 32]));
+            contract.make_move(ChessMove { from: square(from), to: square(to), promotion: None }, String::from(session_id))
+        }
 
-    #[derive(Encode, Decode, Deserialize, Serialize, Clone, Debug, PartialEq, TypeInfo)]
-    pub struct PlayersAddressesLobby {
-        black: Option<[u8; 32]>,
-        white: Option<[u8; 32]>,
-    }
+        #[test]
+        fn session_is_played_to_mate_through_the_data_api() {
+            let store = mock_data_api();
+            // An odd byte makes the creator of a random colour session play black
+            pink::chain_extension::mock::mock_getrandom(|length| alloc::vec![1; length as usize]);
+
+            ink::env::test::set_caller::<PinkEnvironment>(AccountId::from([1; 32]));
+            let contract = new_contract("https://data.mongodb-api.com/app/data-abcde/endpoint/data/v1");
+            let session_id = contract.start_new_game_session(Some(ColourChoice::Random), None, None, false).unwrap();
+            assert_eq!(session_id.len(), 24);
+
+            ink::env::test::set_caller::<PinkEnvironment>(AccountId::from([2; 32]));
+            contract.join_session(session_id.clone()).unwrap();
+            let game_state_lobby = contract.find_lobby_game_session_from_mongodb(session_id.clone()).unwrap();
+            assert_eq!(game_state_lobby.players, PlayersAddressesLobby { white: Some([2; 32]), black: Some([1; 32]) });
+            assert_eq!(game_state_lobby.colour_draw, Some(ColourDraw { random_byte: Some(1), creator_colour: Some(Player::Black) }));
+
+            // Fool's mate
+            assert_eq!(play_through_data_api(&contract, &session_id, 1, "e7", "e5"), Err(NotYourTurn));
+            play_through_data_api(&contract, &session_id, 2, "f2", "f3").unwrap();
+            play_through_data_api(&contract, &session_id, 1, "e7", "e5").unwrap();
+            play_through_data_api(&contract, &session_id, 2, "g2", "g4").unwrap();
+            play_through_data_api(&contract, &session_id, 1, "d8", "h4").unwrap();
+            assert_eq!(play_through_data_api(&contract, &session_id, 2, "e2", "e4"), Err(GameSessionIsOver));
+
+            assert_eq!(store.find_game_session(&session_id).unwrap().status, GameStatus::WonByPlayerBlack);
+            assert_eq!(contract.get_fen(session_id.clone()).unwrap(), "rnb1kbnr/pppp1ppp/8/4p3/6Pq/5P2/PPPPP2P/RNBQKBNR w KQkq - 1 3");
+            assert_eq!(contract.find_players_sessions_track_in_mongodb(), Ok(alloc::vec![session_id]));
+        }
 
-    #[derive(Encode, Decode, Deserialize, Serialize, Clone, Debug, PartialEq, TypeInfo)]
-    pub enum GameStatus {
-        Ongoing, WonByPlayerBlack, WonByPlayerWhite, Stalemate, Draw
-    }
+        #[test]
+        fn data_api_misses_and_cancellations_are_reported() {
+            let store = mock_data_api();
+            ink::env::test::set_caller::<PinkEnvironment>(AccountId::from([1; 32]));
+            let contract = new_contract("https://data.mongodb-api.com/app/data-abcde/endpoint/data/v1");
+            assert_eq!(contract.find_lobby_game_session_from_mongodb(String::from("65f1c0ffee0123456789abcd")).map(|_| ()), Err(NoElementFoundInDB));
+            assert_eq!(contract.find_players_sessions_track_in_mongodb(), Ok(Vec::new()));
+
+            let session_id = contract.start_new_game_session(Some(ColourChoice::Black), None, None, true).unwrap();
+            assert_eq!(contract.make_move(ChessMove { from: square("e2"), to: square("e4"), promotion: None }, session_id.clone()), Err(SessionNeedsSecondPlayer));
+            contract.cancel_session(session_id.clone()).unwrap();
+            assert_eq!(store.find_game_session(&session_id).unwrap().status, GameStatus::Cancelled);
+            assert!(store.find_tracked_sessions([1; 32]).unwrap().is_empty());
+            // The cancellation bumped the version the session was created with
+            assert_eq!(contract.store().update_game_session_status(&session_id, 0, &GameStatus::Expired), Err(ConcurrentModification));
+
+            ink::env::test::set_caller::<PinkEnvironment>(AccountId::from([2; 32]));
+            assert_eq!(contract.join_session(session_id), Err(GameSessionIsOver));
+            assert_eq!(contract.get_rating([2; 32]), Ok(PlayerRating::default()));
+        }
 
-    pub type Board = [[Option<ChessCell>; 8]; 8];
+        #[test]
+        fn only_the_admin_configures_the_mongodb_names() {
+            ink::env::test::set_caller::<PinkEnvironment>(AccountId::from([1; 32]));
+            let mut contract = new_contract("https://example.com");
+            assert_eq!(contract.get_database(), Ok(String::from(DATABASE)));
+            assert!(contract.set_database(String::from("oh_my_chess_staging")));
+            assert!(contract.set_game_sessions_collection(String::from("staging_game_sessions")));
+
+            ink::env::test::set_caller::<PinkEnvironment>(AccountId::from([2; 32]));
+            assert!(!contract.set_data_source(String::from("Cluster1")));
+            assert_eq!(contract.get_data_source(), Err(NotAuthorized));
+
+            // The Data API is asked for the names set by the admin
+            let request_body = Rc::new(RefCell::new(Vec::new()));
+            let captured_body = request_body.clone();
+            pink::chain_extension::mock::mock_http_request(move |request| {
+                *captured_body.borrow_mut() = request.body;
+                HttpResponse::not_found()
+            });
+            assert_eq!(contract.start_new_game_session(Some(ColourChoice::White), None, None, false), Err(ErrorInsertingToDB));
+            let request_body = String::from_utf8(request_body.take()).unwrap();
+            assert!(request_body.contains(r#""collection":"staging_game_sessions""#));
+            assert!(request_body.contains(r#""database":"oh_my_chess_staging""#) && request_body.contains(r#""dataSource":"TestCluster""#));
+        }
 
-    #[derive(Encode, Decode, Deserialize, Serialize, Clone, Debug, TypeInfo)]
-    pub struct GameState {
-        board: Board,
-        turn: Player,
-        players: PlayersAddresses,
-        status: GameStatus,
-    }
+        #[ink::test]
+        fn only_the_admin_selects_the_storage_backend() {
+            ink::env::test::set_caller::<PinkEnvironment>(AccountId::from([1; 32]));
+            let mut contract = new_contract("https://example.com");
+            assert_eq!(contract.get_storage_backend(), Ok(StorageBackend::MongoDB));
 
-    #[derive(Encode, Decode, Deserialize, Serialize, Clone, Debug, TypeInfo)]
-    pub struct GameStateLobby {
-        board: [[Option<ChessCell>; 8]; 8],
-        turn: Player,
-        players: PlayersAddressesLobby,
-        status: GameStatus,
-    }
+            ink::env::test::set_caller::<PinkEnvironment>(AccountId::from([2; 32]));
+            assert!(!contract.set_storage_backend(StorageBackend::LocalCache));
+            assert_eq!(contract.get_storage_backend(), Err(NotAuthorized));
 
-    #[derive(Encode, Decode, Deserialize, Serialize, Copy, Clone, Debug, PartialEq, TypeInfo)]
-    pub struct ChessCell {
-        piece: Piece,
-        player: Player,
-    }
+            ink::env::test::set_caller::<PinkEnvironment>(AccountId::from([1; 32]));
+            assert!(contract.set_storage_backend(StorageBackend::LocalCache));
+            assert_eq!(contract.get_storage_backend(), Ok(StorageBackend::LocalCache));
+        }
 
-    #[derive(Encode, Decode, Deserialize, Serialize, Clone, Debug, PartialEq, TypeInfo)]
-    pub struct ChessMove {
-        from: (u8, u8),
-        to: (u8, u8),
-    }
+        #[test]
+        fn lobbies_changed_since_they_were_found_are_not_expired() {
+            let store = Rc::new(InMemoryStore::new());
+            let mut lobby = position_from_fen(INITIAL_FEN);
+            lobby.players.white = Some([1; 32]);
+            let stale_session_id = store.insert_game_session(&lobby).unwrap();
+            // Cancelled right after the stale lobbies were found
+            lobby.version = 1;
+            let raced_session_id = store.insert_game_session(&lobby).unwrap();
+            for session_id in [&raced_session_id, &stale_session_id] {
+                store.track_session([1; 32], session_id).unwrap();
+            }
 
-    #[derive(Encode, Decode, Deserialize, Clone, Debug)]
-    pub struct FindMongoDBDocumentResult {
-        document: Option<GameStateLobby>
-    }
+            let white = serde_json::to_string(&[1u8; 32]).unwrap();
+            let found_lobbies = format!(r#"{{"documents":[{{"_id":"{}","players":{{"white":{},"black":null}},"created_at":0,"version":0}},{{"_id":"{}","players":{{"white":{},"black":null}},"created_at":0,"version":0}}]}}"#, raced_session_id, white, stale_session_id, white);
+            let fake_store = store.clone();
+            pink::chain_extension::mock::mock_http_request(move |request| {
+                if request.url.ends_with("/find") { HttpResponse::ok(found_lobbies.clone().into_bytes()) }
+                else { handle_data_api_request(&fake_store, request) }
+            });
+            ink::env::test::set_caller::<PinkEnvironment>(AccountId::from([1; 32]));
+            let contract = new_contract("https://example.com");
+
+            assert_eq!(contract.expire_stale_lobbies(), Ok(1));
+            assert_eq!(store.find_game_session(&stale_session_id).unwrap().status, GameStatus::Expired);
+            assert_eq!(store.find_game_session(&raced_session_id).unwrap().status, GameStatus::Ongoing);
+            assert_eq!(store.find_tracked_sessions([1; 32]).unwrap(), [raced_session_id]);
+        }
 
-    #[derive(Deserialize, Clone, Debug)]
-    pub struct MongoDBSessionDocument {
-        sessions: heapless::Vec<heapless::String<32>, 10>
-    }
+        #[test]
+        fn ratings_failing_to_apply_leave_the_game_over_and_pending() {
+            let store = Rc::new(InMemoryStore::new());
+            let ratings_unavailable = Rc::new(Cell::new(true));
+            let (fake_store, fake_ratings_unavailable) = (store.clone(), ratings_unavailable.clone());
+            pink::chain_extension::mock::mock_http_request(move |request| {
+                let is_rating_update = request.url.ends_with("/updateOne") && String::from_utf8_lossy(&request.body).contains(PLAYER_RATINGS);
+                if is_rating_update && fake_ratings_unavailable.get() { HttpResponse::not_found() }
+                else { handle_data_api_request(&fake_store, request) }
+            });
+            ink::env::test::set_caller::<PinkEnvironment>(AccountId::from([1; 32]));
+            let contract = new_contract("https://example.com");
+            let session_id = contract.start_new_game_session(Some(ColourChoice::White), Some([2; 32]), None, true).unwrap();
+
+            ink::env::test::set_caller::<PinkEnvironment>(AccountId::from([2; 32]));
+            assert_eq!(contract.resign(session_id.clone()), Ok(()));
+            let game_state_lobby = store.find_game_session(&session_id).unwrap();
+            assert_eq!(game_state_lobby.status, GameStatus::WonByPlayerWhite);
+            assert!(!game_state_lobby.ratings_update.unwrap().white_applied);
+            assert_eq!(contract.get_rating([1; 32]), Ok(PlayerRating::default()));
+
+            ratings_unavailable.set(false);
+            assert_eq!(contract.apply_ratings(session_id.clone()), Ok(()));
+            let ratings_update = store.find_game_session(&session_id).unwrap().ratings_update.unwrap();
+            assert!(ratings_update.white_applied && ratings_update.black_applied);
+            assert!(contract.get_rating([1; 32]).unwrap().rating > PlayerRating::default().rating);
+        }
 
-    #[derive(Deserialize, Clone, Debug)]
-    pub struct FindMongoDBTrackDocumentResult {
-        document: Option<MongoDBSessionDocument>
-    }
+        #[test]
+        fn documents_and_trackers_are_not_capped_in_size() {
+            let store = mock_data_api();
+            ink::env::test::set_caller::<PinkEnvironment>(AccountId::from([1; 32]));
+            let contract = new_contract("https://data.mongodb-api.com/app/data-abcde/endpoint/data/v1");
+            let session_ids: Vec<String> = (0..12).map(|_| contract.start_new_game_session(Some(ColourChoice::White), Some([2; 32]), None, false).unwrap()).collect();
+            assert_eq!(contract.find_players_sessions_track_in_mongodb(), Ok(session_ids.clone()));
+
+            // A long game outgrows the 4 KB the documents used to be serialized into
+            let mut game_state = OhMyChess::find_started_game_session(&*store, &session_ids[0]).unwrap();
+            for ply in 0..200 {
+                game_state.moves.push(JsonString(format!("Nf{}", ply % 8 + 1)));
+                game_state.position_history.push(JsonString(String::from(INITIAL_FEN)));
+            }
+            game_state.version = 1;
+            assert!(serde_json::to_string(&game_state).unwrap().len() > 4096);
+            contract.store().update_game_session(&session_ids[0], 0, &game_state).unwrap();
 
-    #[allow(non_snake_case)]
-    #[derive(Deserialize, Clone, Debug)]
-    pub struct InsertedMongoDBDocumentResult {
-        insertedId: Option<heapless::String<32>>
+            let game_state_lobby = contract.find_lobby_game_session_from_mongodb(session_ids[0].clone()).unwrap();
+            assert_eq!(game_state_lobby.moves, game_state.moves);
+            assert_eq!(game_state_lobby.position_history.len(), 201);
+        }
     }
-
-
 }
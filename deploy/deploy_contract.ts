@@ -13,6 +13,11 @@ interface CliArgs {
     adminProfile: string;
     mongoAtlasUrl: string;
     mongoAtlasApiKey: string;
+    mongoAtlasDataSource: string;
+    mongoAtlasDatabase: string;
+    gameSessionsCollection: string;
+    playersSessionsCollection: string;
+    playerRatingsCollection: string;
     endpoint: string;
 }
 
@@ -32,6 +37,11 @@ function parseArguments(rawArgs: string[]): CliArgs {
             '--admin_profile': String,
             '--mongo_atlas_url': String,
             '--mongo_atlas_api_key': String,
+            '--mongo_atlas_data_source': String,
+            '--mongo_atlas_database': String,
+            '--game_sessions_collection': String,
+            '--players_sessions_collection': String,
+            '--player_ratings_collection': String,
             '--endpoint': String,
             // Aliases
             '-n': '--contract_name',
@@ -40,6 +50,8 @@ function parseArguments(rawArgs: string[]): CliArgs {
             '-a': '--admin_profile',
             '-u': '--mongo_atlas_url',
             '-k': '--mongo_atlas_api_key',
+            '-s': '--mongo_atlas_data_source',
+            '-d': '--mongo_atlas_database',
             '-e': '--endpoint',
         },
         {
@@ -54,6 +66,11 @@ function parseArguments(rawArgs: string[]): CliArgs {
         adminProfile: args['--admin_profile'] || 'Alice',
         mongoAtlasUrl: args['--mongo_atlas_url'],
         mongoAtlasApiKey: args['--mongo_atlas_api_key'],
+        mongoAtlasDataSource: args['--mongo_atlas_data_source'] || 'Cluster0',
+        mongoAtlasDatabase: args['--mongo_atlas_database'] || 'hackathon',
+        gameSessionsCollection: args['--game_sessions_collection'] || 'game_sessions',
+        playersSessionsCollection: args['--players_sessions_collection'] || 'players_sessions_trackers',
+        playerRatingsCollection: args['--player_ratings_collection'] || 'player_ratings',
         endpoint: args['--endpoint'] || 'ws://localhost:9944/ws',
     };
 }
@@ -108,7 +125,16 @@ async function main(argv: string[]): Promise<void> {
 
     const blueprint = pinkCodePromise.getBlueprint();
 
-    const result = await blueprint.send.new({ provider }, args.mongoAtlasUrl, args.mongoAtlasApiKey);
+    const result = await blueprint.send.new(
+        { provider },
+        args.mongoAtlasUrl,
+        args.mongoAtlasApiKey,
+        args.mongoAtlasDataSource,
+        args.mongoAtlasDatabase,
+        args.gameSessionsCollection,
+        args.playersSessionsCollection,
+        args.playerRatingsCollection,
+    );
     await result.waitFinalized();
     const contract = result.contract;
     console.log(contract);
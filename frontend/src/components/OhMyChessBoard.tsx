@@ -9,7 +9,8 @@ import {
     GameSession,
     gameSessionToFen,
     GameStatus,
-    getBoardOrientation
+    getBoardOrientation,
+    Piece as ContractPiece,
 } from "@/models/game-session";
 import { InjectedAccount } from "@phala/sdk";
 import Image from "next/image";
@@ -23,6 +24,14 @@ const boardWrapper = {
     margin: "3rem auto",
 };
 
+// Map chess.js promotion symbols to the contract pieces
+const promotionSymbolToPiece: { [key: string]: ContractPiece } = {
+    'n': ContractPiece.Knight,
+    'b': ContractPiece.Bishop,
+    'r': ContractPiece.Rook,
+    'q': ContractPiece.Queen,
+};
+
 const chessNotationToTuple = (square: Square): ChessLocation => {
     // Map for columns 'a' through 'h' to 0 through 7
     const columnMap: { [key: string]: number } = {
@@ -159,7 +168,8 @@ const OhMyChessBoard = ({networkUrl, accountAddress, sessionId}: Props): ReactNo
             game.undo();
 
             if (move && isPlayerTurn && gameSession?.players.black && gameSession?.players.white) {
-                useMakeChessMoveMutation.mutate({sessionId: sessionId as string, chessMove: {from, to}}, {
+                const promotion = move.promotion ? promotionSymbolToPiece[move.promotion] : null;
+                useMakeChessMoveMutation.mutate({sessionId: sessionId as string, chessMove: {from, to, promotion}}, {
                     onSuccess: async() => {
                         game.move({
                             from: sourceSquare,
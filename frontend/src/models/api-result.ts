@@ -1,5 +1,5 @@
 import { InnerError, OuterError, Result } from "@/models/result";
-import {GameSession} from "@/models/game-session";
+import {ChessMove, GameSession} from "@/models/game-session";
 
 export type ApiResult<T> = Result<Result<T, InnerError>, OuterError>;
 
@@ -12,3 +12,5 @@ export type JoinSessionResult = ApiResult<void>;
 export type GetGameSessionResult = ApiResult<GameSession>;
 
 export type MakeChessMoveResult = ApiResult<void>;
+
+export type LegalMovesResult = ApiResult<ChessMove[]>;
@@ -6,7 +6,48 @@ export interface GameSession {
     board: ChessCell[][];
     turn: Player;
     players: PlayersAddresses;
+    colourDraw: ColourDraw | null;
     status: GameStatus;
+    createdAt: number;
+    drawOffer: Player | null;
+    takebackRequest: Player | null;
+    clock: Clock | null;
+    rated: boolean;
+    version: number;
+    castlingRights: CastlingRights;
+    enPassant: ChessLocation | null;
+    halfmoveClock: number;
+    fullmoveNumber: number;
+    moves: string[];
+    startingFen: string | null;
+}
+
+// With a random colour, the creator's colour is drawn when the second player joins
+export type ColourChoice = Player | 'Random';
+
+export interface ColourDraw {
+    randomByte: number | null;
+    creatorColour: Player | null;
+}
+
+// Durations in milliseconds
+export type TimeControl =
+    | { fischer: { base: number; increment: number } }
+    | { bronstein: { base: number; delay: number } }
+    | { correspondence: { daysPerMove: number } };
+
+export interface Clock {
+    timeControl: TimeControl;
+    whiteRemaining: number;
+    blackRemaining: number;
+    lastMoveTimestamp: number;
+}
+
+export interface CastlingRights {
+    whiteKingSide: boolean;
+    whiteQueenSide: boolean;
+    blackKingSide: boolean;
+    blackQueenSide: boolean;
 }
 
 export interface PlayersAddresses {
@@ -21,6 +62,12 @@ export enum Player {
 
 export type ChessLocation = [number, number];
 
+export interface ChessMove {
+    from: ChessLocation;
+    to: ChessLocation;
+    promotion: Piece | null;
+}
+
 export enum Piece {
     Pawn = 'Pawn',
     Knight = 'Knight',
@@ -41,6 +88,8 @@ export enum GameStatus {
     WonByPlayerWhite = 'WonByPlayerWhite',
     Stalemate = 'Stalemate',
     Draw = 'Draw',
+    Cancelled = 'Cancelled',
+    Expired = 'Expired',
 }
 
 // Helper function to get FEN piece code
@@ -70,15 +119,23 @@ export const gameSessionToFen = (gameSession: GameSession): string => {
     // Active color
     const activeColor = gameSession.turn === Player.White ? 'w' : 'b';
 
-    // We'll assume castling availability and en passant target square are not provided and default to '-'
-    // If your GameSession object includes this information, modify this section accordingly
-    const castlingAvailability = '-';
-    const enPassantTargetSquare = '-';
-
-    // We'll also assume the halfmove and fullmove counters are not provided and default to '0' and '1'
-    // You would need to provide these values if your GameSession object includes them
-    const halfmoveClock = '0';
-    const fullmoveNumber = '1';
+    // Castling availability
+    const { whiteKingSide, whiteQueenSide, blackKingSide, blackQueenSide } = gameSession.castlingRights;
+    const castlingAvailability = [
+        whiteKingSide ? 'K' : '',
+        whiteQueenSide ? 'Q' : '',
+        blackKingSide ? 'k' : '',
+        blackQueenSide ? 'q' : '',
+    ].join('') || '-';
+
+    // En passant target square
+    const enPassantTargetSquare = gameSession.enPassant
+        ? `${'abcdefgh'[gameSession.enPassant[1]]}${gameSession.enPassant[0] + 1}`
+        : '-';
+
+    // Halfmove clock and fullmove number
+    const halfmoveClock = gameSession.halfmoveClock.toString();
+    const fullmoveNumber = gameSession.fullmoveNumber.toString();
 
     // Construct the full FEN string
     return `${fenRows} ${activeColor} ${castlingAvailability} ${enPassantTargetSquare} ${halfmoveClock} ${fullmoveNumber}`;
@@ -10,15 +10,18 @@ import {
     CreatedNewSessionResult,
     GetGameSessionResult,
     JoinSessionResult,
+    LegalMovesResult,
     ListSessionsResult, MakeChessMoveResult
 } from "@/models/api-result";
-import { GameSession, Player } from "@/models/game-session";
+import { ChessLocation, ChessMove, ColourChoice, GameSession, Piece, TimeControl } from "@/models/game-session";
 
 
 
 export interface CreateNewSessionArgs {
-    player?: Player;
+    player?: ColourChoice;
     secondPlayerAddress?: Uint8Array;
+    timeControl?: TimeControl;
+    rated?: boolean;
 }
 
 export interface JoinSessionArgs {
@@ -34,9 +37,15 @@ export interface MakeChessMoveArgs {
     chessMove: {
         from: [number, number];
         to: [number, number];
+        promotion: Piece | null;
     };
 }
 
+export interface GetLegalMovesArgs {
+    sessionId: string;
+    from?: ChessLocation;
+}
+
 export interface OhMyChessClient {
     contract: PinkContractPromise;
     fetchSessions: () => Promise<string[]>;
@@ -44,6 +53,7 @@ export interface OhMyChessClient {
     joinSession: (_: JoinSessionArgs) => Promise<void>;
     getGameSession: (_: GetGameSessionArgs) => Promise<GameSession>;
     makeChessMove: (_: MakeChessMoveArgs) => Promise<void>;
+    getLegalMoves: (_: GetLegalMovesArgs) => Promise<ChessMove[]>;
     clientId: string;
 }
 
@@ -75,8 +85,8 @@ export const instantiateOhMyChessClient = async({networkUrl, accountAddress}: In
         else return listSessionsResult.ok.ok;
     }
 
-    const createNewSession = async ({player, secondPlayerAddress}: CreateNewSessionArgs): Promise<string> => {
-        const contractCallOutcome = await contract.q.startNewGameSession({args: [player || null, secondPlayerAddress || null]});
+    const createNewSession = async ({player, secondPlayerAddress, timeControl, rated}: CreateNewSessionArgs): Promise<string> => {
+        const contractCallOutcome = await contract.q.startNewGameSession({args: [player || null, secondPlayerAddress || null, timeControl || null, rated || false]});
         const newCreatedSessionId = contractCallOutcome.output.toJSON()?.valueOf() as CreatedNewSessionResult;
         if ('err' in newCreatedSessionId) throw Error(newCreatedSessionId.err);
         else if ('err' in newCreatedSessionId.ok) throw Error(newCreatedSessionId.ok.err);
@@ -107,8 +117,16 @@ export const instantiateOhMyChessClient = async({networkUrl, accountAddress}: In
         else return makeChessMoveResult.ok.ok;
     };
 
+    const getLegalMoves = async({sessionId, from}: GetLegalMovesArgs): Promise<ChessMove[]> => {
+        const contractCallOutcome = await contract.q.legalMoves({args: [sessionId, from || null]});
+        const legalMovesResult = contractCallOutcome.output.toJSON()?.valueOf() as LegalMovesResult;
+        if ('err' in legalMovesResult) throw Error(legalMovesResult.err);
+        else if ('err' in legalMovesResult.ok) throw Error(legalMovesResult.ok.err);
+        else return legalMovesResult.ok.ok;
+    };
+
     const clientId = `${networkUrl}-${accountAddress.address}`
 
-    return {contract, fetchSessions, createNewSession, joinSession, getGameSession, makeChessMove, clientId};
+    return {contract, fetchSessions, createNewSession, joinSession, getGameSession, makeChessMove, getLegalMoves, clientId};
 };
 